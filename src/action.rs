@@ -1,78 +1,650 @@
 use failure::Error;
-use handlebars::Handlebars;
+use handlebars::{Context, Handlebars, RenderContext, Renderable, StringOutput};
+use log::info;
+use std::collections::HashSet;
 use std::io::prelude::*;
-use subprocess::Exec;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use subprocess::{Exec, ExitStatus, Popen, Redirection};
+
+use crate::errors::EachError;
+use crate::helpers;
+use crate::writers;
+
+/// How long a timed out process has to exit after SIGTERM before it's killed.
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/// What's done for an item: a process to run, or with `--map`, text to print.
+enum Task {
+	Exec(Exec),
+	Print(String),
+}
+
+/// A command rendered for one item, along with the data to pass to its stdin and the file to
+/// write its output to, if any.
+pub struct PreparedCommand {
+	task: Task,
+	stdin: Option<String>,
+	output: Option<PathBuf>,
+}
+
+impl PreparedCommand {
+	/// The command line, or the text that's printed.
+	fn cmdline(&self) -> String {
+		match self.task {
+			Task::Exec(ref exec) => exec.to_cmdline_lossy(),
+			Task::Print(ref text) => text.clone(),
+		}
+	}
+}
 
 pub struct Action<'a> {
 	command: String,
+	/// A field holding each item's own command, which is run instead of `command`.
+	pub command_field: Option<String>,
 	args: Vec<String>,
+	env: Vec<(String, String)>,
+	pub env_clear: bool,
+	/// Whether the command is a template run by the shell, as set by `set_shell`.
+	shell: bool,
+	pub timeout: Option<Duration>,
+	/// When the whole run has to stop, killing any commands still running.
+	pub deadline: Option<Instant>,
+	pub retries: usize,
+	pub retry_delay: Duration,
+	pub retry_backoff: bool,
+	pub keep_going: bool,
+	pub ignore_exit: bool,
+	pub dry_run: bool,
+	pub print_command: bool,
+	pub review: bool,
+	pub progress: bool,
+	/// Whether prompts and the progress bar are colored.
+	pub color: bool,
+	pub collect: bool,
+	pub collect_json: bool,
+	pub stream: bool,
+	cwd: bool,
 	stdin: bool,
+	/// A field passed to stdin as it is, rather than through a template.
+	pub stdin_field: Option<String>,
 	pub prompt: bool,
 	pub prompt_stdin: bool,
+	/// Whether the command template is rendered and printed rather than run, as set by `set_map`.
+	map: bool,
+	output: bool,
+	output_append: bool,
+	/// Whether output files which already exist are added to, rather than replaced.
+	pub append_existing: bool,
+	/// Output files written so far, to catch items which render the same path.
+	output_paths: Mutex<HashSet<PathBuf>>,
+	/// Whether any template refers to `@total` or `@last`, which need every item at once.
+	uses_total: bool,
 	templates: Handlebars<'a>,
 }
 
+fn uses_total(template: &str) -> bool {
+	template.contains("@total") || template.contains("@last")
+}
+
 impl<'a> Action<'a> {
 	pub fn new(
 		command: String,
 		stdin: Option<String>,
 		args: Vec<String>,
+		env: Vec<(String, String)>,
+		cwd: Option<String>,
 		prompt: bool,
 		prompt_stdin: bool,
 	) -> Result<Action<'a>, Error> {
+		let uses_total = args.iter().any(|arg| uses_total(arg))
+			|| env.iter().any(|(_, value)| uses_total(value))
+			|| cwd.as_deref().is_some_and(uses_total)
+			|| stdin.as_deref().is_some_and(uses_total);
+
 		let mut templates = Handlebars::new();
+		// These are command lines, not HTML, so values are used as they are.
+		templates.register_escape_fn(handlebars::no_escape);
+		helpers::register(&mut templates);
+
 		let args: Result<Vec<String>, Error> = args
 			.iter()
 			.enumerate()
 			.map(|(i, arg)| -> Result<String, Error> {
 				let name = i.to_string();
-				templates.register_template_string(&name, &arg)?;
+				templates.register_template_string(&name, arg)?;
 				Ok(name)
 			})
 			.collect();
 
+		let env: Result<Vec<(String, String)>, Error> = env
+			.into_iter()
+			.enumerate()
+			.map(|(i, (key, value))| -> Result<(String, String), Error> {
+				let name = format!("env{}", i);
+				templates.register_template_string(&name, value)?;
+				Ok((key, name))
+			})
+			.collect();
+
+		if let Some(ref cwd) = cwd {
+			templates.register_template_string("cwd", cwd)?;
+		}
+
 		if let Some(ref stdin) = stdin {
 			templates.register_template_string("stdin", stdin)?;
 		}
 
 		Ok(Action {
 			command,
+			command_field: None,
 			args: args?,
+			env: env?,
+			env_clear: false,
+			shell: false,
+			timeout: None,
+			deadline: None,
+			retries: 0,
+			retry_delay: Duration::ZERO,
+			retry_backoff: false,
+			keep_going: false,
+			ignore_exit: false,
+			dry_run: false,
+			print_command: false,
+			review: false,
+			progress: false,
+			color: false,
+			collect: false,
+			collect_json: false,
+			stream: false,
+			cwd: cwd.is_some(),
 			stdin: stdin.is_some(),
+			stdin_field: None,
 			prompt,
 			prompt_stdin,
+			map: false,
+			output: false,
+			output_append: false,
+			append_existing: false,
+			output_paths: Mutex::new(HashSet::new()),
+			uses_total,
 			templates,
 		})
 	}
 
-	pub fn prepare(&self, value: &serde_json::Value) -> Result<Exec, Error> {
-		let mut cmd = Exec::cmd(&self.command);
-		for arg in &self.args {
-			cmd = cmd.arg(self.templates.render(arg, value)?);
-		}
+	/// Runs the whole command line with the shell, rendered as one template, rather than the
+	/// command as a program with each argument rendered separately.
+	pub fn set_shell(&mut self, shell: bool) -> Result<(), Error> {
+		self.shell = shell;
+		self.register_command()
+	}
 
-		if self.stdin {
-			cmd = cmd.stdin(self.templates.render("stdin", value)?.as_str());
+	/// Renders the command template and prints it for each item, rather than running anything.
+	pub fn set_map(&mut self, map: bool) -> Result<(), Error> {
+		self.map = map;
+		self.register_command()
+	}
+
+	/// The command is only a template in shell or map mode, and otherwise it's the program's name
+	/// as it is.
+	fn register_command(&mut self) -> Result<(), Error> {
+		if (self.shell || self.map) && !self.templates.has_template("command") {
+			self.templates
+				.register_template_string("command", &self.command)?;
+			self.uses_total |= uses_total(&self.command);
 		}
+		Ok(())
+	}
+
+	/// Makes rendering fail when a template refers to a missing field, rather than leaving it empty.
+	pub fn set_strict(&mut self, strict: bool) {
+		self.templates.set_strict_mode(strict);
+	}
+
+	/// Whether rendering fails on missing fields, as set by `set_strict`.
+	pub fn is_strict(&self) -> bool {
+		self.templates.strict_mode()
+	}
+
+	/// Writes each command's output to the file `template` renders to, rather than stdout. Unless
+	/// `append` is set, it's an error for two items to render the same file.
+	pub fn set_output(&mut self, template: &str, append: bool) -> Result<(), Error> {
+		self.templates
+			.register_template_string("output", template)?;
+		self.output = true;
+		self.output_append = append;
+		self.uses_total |= uses_total(template);
+		Ok(())
+	}
 
-		Ok(cmd)
+	/// Whether rendering needs the total number of items, so they can't be processed in batches.
+	pub fn uses_total(&self) -> bool {
+		self.uses_total
 	}
 
-	pub fn prompt(&self, cmd: &Exec, value: &serde_json::Value) -> Result<String, Error> {
-		let cmd_str = cmd.to_cmdline_lossy();
+	/// Renders a template with the item as its context, its position in the input as `@index`
+	/// and whether it's the `@first`. If the number of items is known, it's given as `@total`
+	/// along with whether the item is the `@last`.
+	fn render(
+		&self,
+		name: &str,
+		value: &serde_json::Value,
+		index: usize,
+		total: Option<usize>,
+	) -> Result<String, Error> {
+		render_item(&self.templates, name, value, index, total)
+	}
+
+	pub fn prepare(
+		&self,
+		value: &serde_json::Value,
+		index: usize,
+		total: Option<usize>,
+	) -> Result<PreparedCommand, Error> {
+		if self.map {
+			return Ok(PreparedCommand {
+				task: Task::Print(self.render("command", value, index, total)?),
+				stdin: None,
+				output: self.output_path(value, index, total)?,
+			});
+		}
+
+		let mut cmd = if let Some(ref field) = self.command_field {
+			match value.get(field) {
+				// A string is a whole command line for the shell, like in shell mode. Any arguments
+				// are passed to it as positional parameters, so the shell never parses them.
+				Some(serde_json::Value::String(line)) => {
+					let shell = std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string());
+					let mut cmd = match self.args.is_empty() {
+						true => Exec::cmd(&shell).arg("-c").arg(line),
+						false => Exec::cmd(&shell)
+							.arg("-c")
+							.arg(format!("{} \"$@\"", line))
+							.arg(&shell),
+					};
+					for arg in &self.args {
+						cmd = cmd.arg(self.render(arg, value, index, total)?);
+					}
+					cmd
+				}
+				// An array is the program and its arguments, which are run as they are.
+				Some(serde_json::Value::Array(words)) if !words.is_empty() => {
+					let mut words = words.iter().map(|word| match word {
+						serde_json::Value::String(word) => Ok(word.clone()),
+						_ => Err(bad_command_field(field)),
+					});
+					let mut cmd = Exec::cmd(words.next().unwrap()?);
+					for word in words {
+						cmd = cmd.arg(word?);
+					}
+					for arg in &self.args {
+						cmd = cmd.arg(self.render(arg, value, index, total)?);
+					}
+					cmd
+				}
+				_ => return Err(bad_command_field(field).into()),
+			}
+		} else if self.shell {
+			let mut line = self.render("command", value, index, total)?;
+			for arg in &self.args {
+				line.push(' ');
+				line.push_str(&self.render(arg, value, index, total)?);
+			}
 
-		Ok(if self.prompt_stdin {
-			let stdin = self.templates.render("stdin", value)?;
-			format!("# Stdin:\n{}\n- Command:\n{}\n", &stdin, &cmd_str)
+			let shell = std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string());
+			Exec::cmd(shell).arg("-c").arg(line)
 		} else {
-			cmd_str
+			let mut cmd = Exec::cmd(&self.command);
+			for arg in &self.args {
+				cmd = cmd.arg(self.render(arg, value, index, total)?);
+			}
+			cmd
+		};
+
+		if self.env_clear {
+			cmd = cmd.env_clear();
+		}
+
+		for (key, name) in &self.env {
+			cmd = cmd.env(key, self.render(name, value, index, total)?);
+		}
+
+		if self.cwd {
+			let cwd = self.render("cwd", value, index, total)?;
+			if !Path::new(&cwd).is_dir() {
+				return Err(EachError::Data {
+					message: format!("Working directory {:?} doesn't exist", cwd),
+				}
+				.into());
+			}
+
+			cmd = cmd.cwd(cwd);
+		}
+
+		// The stdin data is kept aside rather than given to the Exec, so the process can be
+		// started without waiting for it and killed if it runs too long.
+		let stdin = match (self.stdin, &self.stdin_field) {
+			(true, _) => Some(self.render("stdin", value, index, total)?),
+			(false, Some(field)) => Some(stdin_field(value, field)?),
+			(false, None) => None,
+		};
+		if stdin.is_some() {
+			cmd = cmd.stdin(Redirection::Pipe);
+		}
+
+		Ok(PreparedCommand {
+			task: Task::Exec(cmd),
+			stdin,
+			output: self.output_path(value, index, total)?,
 		})
 	}
 
-	pub fn run(&self, cmd: Exec) -> Result<(), Error> {
-		let result = cmd.capture()?;
-		std::io::stdout().write_all(&result.stdout)?;
-		std::io::stderr().write_all(&result.stderr)?;
-		Ok(())
+	fn output_path(
+		&self,
+		value: &serde_json::Value,
+		index: usize,
+		total: Option<usize>,
+	) -> Result<Option<PathBuf>, Error> {
+		Ok(match self.output {
+			true => Some(PathBuf::from(self.render("output", value, index, total)?)),
+			false => None,
+		})
+	}
+
+	pub fn prompt(&self, cmd: &PreparedCommand) -> String {
+		self.describe(cmd, self.prompt_stdin)
 	}
+
+	/// The command line, preceded by the stdin passed to it if `with_stdin` is set.
+	pub fn describe(&self, cmd: &PreparedCommand, with_stdin: bool) -> String {
+		let cmd_str = cmd.cmdline();
+
+		match (with_stdin, &cmd.stdin) {
+			(true, Some(stdin)) => format!("# Stdin:\n{}\n- Command:\n{}\n", stdin, &cmd_str),
+			_ => cmd_str,
+		}
+	}
+
+	/// Whether the `deadline` has passed, so nothing more should be run.
+	pub fn past_deadline(&self) -> bool {
+		self.deadline
+			.is_some_and(|deadline| Instant::now() >= deadline)
+	}
+
+	/// How long a command may run for: its timeout, or until the deadline if that's sooner.
+	fn time_limit(&self) -> Option<Duration> {
+		let until_deadline = self
+			.deadline
+			.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+		match (self.timeout, until_deadline) {
+			(Some(timeout), Some(until_deadline)) => Some(timeout.min(until_deadline)),
+			(timeout, until_deadline) => timeout.or(until_deadline),
+		}
+	}
+
+	/// Runs the command, returning its output as a value if collecting, otherwise writing it
+	/// straight to stdout. If it fails it's run again, up to `retries` more times.
+	pub fn run(&self, cmd: PreparedCommand) -> Result<Option<serde_json::Value>, Error> {
+		let cmd_str = cmd.cmdline();
+		let mut delay = self.retry_delay;
+		let mut attempt = 0;
+		let mut claimed = false;
+		let stdout = loop {
+			match self.run_once(&cmd, &cmd_str, &mut claimed) {
+				Err(e) if attempt < self.retries && !self.past_deadline() => {
+					attempt += 1;
+					info!(
+						"Retrying ({}/{}) in {:?} after error: {}",
+						attempt, self.retries, delay, e
+					);
+					thread::sleep(delay);
+					if self.retry_backoff {
+						delay *= 2;
+					}
+				}
+				result => break result?,
+			}
+		};
+
+		if !self.collect {
+			return Ok(None);
+		}
+
+		if self.collect_json {
+			return Ok(Some(serde_json::from_slice(&stdout).map_err(|e| {
+				EachError::Data {
+					message: format!("Output of {} isn't valid JSON: {}", cmd_str, e),
+				}
+			})?));
+		}
+
+		// Most commands end their output with a newline, which isn't part of the value.
+		let output = String::from_utf8_lossy(&stdout);
+		let output = output.strip_suffix('\n').unwrap_or(&output);
+		Ok(Some(output.strip_suffix('\r').unwrap_or(output).into()))
+	}
+
+	/// Runs the command once, returning its stdout if collecting. `claimed` is set once it has
+	/// written to its output file, so a retry replaces the output of the attempt before.
+	fn run_once(
+		&self,
+		cmd: &PreparedCommand,
+		cmd_str: &str,
+		claimed: &mut bool,
+	) -> Result<Vec<u8>, Error> {
+		let output = cmd.output.as_deref();
+		let exec = match cmd.task {
+			Task::Exec(ref exec) => exec,
+			// Printed text is written like a command's output, as a line of its own.
+			Task::Print(ref text) => {
+				let line = format!("{}\n", text).into_bytes();
+				return self.write_capture((Some(line), None), output, claimed);
+			}
+		};
+
+		// Streamed output goes straight to our stdout rather than being captured.
+		let exec = match self.stream && !self.collect && output.is_none() {
+			true => exec.clone(),
+			false => exec.clone().stdout(Redirection::Pipe),
+		};
+		let mut process = exec.popen()?;
+		let mut communicator = process.communicate_start(cmd.stdin.clone().map(String::into_bytes));
+		let time_limit = self.time_limit();
+		if let Some(time_limit) = time_limit {
+			communicator = communicator.limit_time(time_limit);
+		}
+
+		let started = Instant::now();
+		let (stdout, stderr) = match communicator.read() {
+			Ok(capture) => capture,
+			Err(e) if e.kind() == ErrorKind::TimedOut => {
+				self.write_capture(e.capture, output, claimed)?;
+				return Err(timed_out(&mut process, cmd_str, self.past_deadline()));
+			}
+			Err(e) => return Err(e.error.into()),
+		};
+		let stdout = self.write_capture((stdout, stderr), output, claimed)?;
+
+		// The process may close its output before it actually exits.
+		let status = match time_limit {
+			Some(time_limit) => {
+				let remaining = time_limit.saturating_sub(started.elapsed());
+				match process.wait_timeout(remaining)? {
+					Some(status) => status,
+					None => return Err(timed_out(&mut process, cmd_str, self.past_deadline())),
+				}
+			}
+			None => process.wait()?,
+		};
+
+		if !status.success() && !self.ignore_exit {
+			let reason = match status {
+				ExitStatus::Exited(code) => format!("exited with code {}", code),
+				ExitStatus::Signaled(signal) => format!("was killed by signal {}", signal),
+				other => format!("failed: {:?}", other),
+			};
+
+			return Err(EachError::Data {
+				message: format!("Command {}: {}", reason, cmd_str),
+			}
+			.into());
+		}
+
+		Ok(stdout)
+	}
+
+	/// Writes the output of a process, except stdout when collecting, which is returned.
+	fn write_capture(
+		&self,
+		(stdout, stderr): (Option<Vec<u8>>, Option<Vec<u8>>),
+		output: Option<&Path>,
+		claimed: &mut bool,
+	) -> Result<Vec<u8>, Error> {
+		let stdout = stdout.unwrap_or_default();
+		std::io::stderr().write_all(&stderr.unwrap_or_default())?;
+		if self.collect {
+			return Ok(stdout);
+		}
+
+		match output {
+			Some(path) => self.write_output(path, claimed, &stdout)?,
+			None => std::io::stdout().write_all(&stdout)?,
+		}
+
+		Ok(Vec::new())
+	}
+
+	/// Writes to an item's output file, replacing it the first time it's used, or when an item
+	/// which already `claimed` it is retried. The lock is held throughout so that appends can't
+	/// land before the file is truncated.
+	fn write_output(&self, path: &Path, claimed: &mut bool, data: &[u8]) -> Result<(), Error> {
+		let mut paths = self.output_paths.lock().unwrap_or_else(|e| e.into_inner());
+		let first = paths.insert(path.to_path_buf()) || (*claimed && !self.output_append);
+		if !first && !self.output_append {
+			return Err(EachError::Data {
+				message: format!("Output file {:?} is used by more than one item", path),
+			}
+			.into());
+		}
+		*claimed = true;
+
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+
+		let mut file = writers::create_file(path, !first || self.append_existing)?;
+		file.write_all(data)?;
+		Ok(file.finish()?)
+	}
+}
+
+/// Renders a template registered as `name` the same way as `Action::render`, for templates
+/// which don't belong to an action.
+pub fn render_item(
+	templates: &Handlebars,
+	name: &str,
+	value: &serde_json::Value,
+	index: usize,
+	total: Option<usize>,
+) -> Result<String, Error> {
+	let template = match templates.get_template(name) {
+		Some(template) => template,
+		None => {
+			return Err(EachError::Usage {
+				message: format!("No template named {}", name),
+			}
+			.into())
+		}
+	};
+
+	let ctx = Context::wraps(value)?;
+	let mut rc = RenderContext::new(template.name.as_ref());
+	if let Some(block) = rc.block_mut() {
+		block.set_local_var("index", index.into());
+		block.set_local_var("first", (index == 0).into());
+		if let Some(total) = total {
+			block.set_local_var("total", total.into());
+			block.set_local_var("last", (index + 1 == total).into());
+		}
+	}
+
+	let mut out = StringOutput::new();
+	template.render(templates, &ctx, &mut rc, &mut out)?;
+	Ok(out.into_string()?)
+}
+
+/// Rewrites a template written with `open` and `close` around its expressions (e.g. `<<name>>`)
+/// as a Handlebars one, escaping any `{{` already in it so it's left as it is.
+pub fn convert_delimiters(template: &str, open: &str, close: &str) -> Result<String, EachError> {
+	let mut converted = String::with_capacity(template.len());
+	let mut rest = template;
+
+	while !rest.is_empty() {
+		if let Some(expr) = rest.strip_prefix(open) {
+			let end = expr.find(close).ok_or_else(|| EachError::Usage {
+				message: format!("Unclosed {} in template: {}", open, template),
+			})?;
+
+			// A backslash right before an expression would escape it, so it needs escaping too.
+			if converted.ends_with('\\') {
+				converted.push('\\');
+			}
+
+			converted.push_str("{{");
+			converted.push_str(&expr[..end]);
+			converted.push_str("}}");
+			rest = &expr[end + close.len()..];
+		} else if let Some(literal) = rest.strip_prefix("{{") {
+			converted.push_str("\\{{");
+			rest = literal;
+		} else {
+			let c = rest.chars().next().unwrap_or_default();
+			converted.push(c);
+			rest = &rest[c.len_utf8()..];
+		}
+	}
+
+	Ok(converted)
+}
+
+fn bad_command_field(field: &str) -> EachError {
+	EachError::Data {
+		message: format!(
+			"The {} field must be a command line or an array of the program and its arguments",
+			field
+		),
+	}
+}
+
+/// The stdin taken straight from an item's field: a string as it is, nothing for null and
+/// anything else as JSON.
+fn stdin_field(value: &serde_json::Value, field: &str) -> Result<String, EachError> {
+	match value.get(field) {
+		Some(serde_json::Value::String(s)) => Ok(s.clone()),
+		Some(serde_json::Value::Null) => Ok(String::new()),
+		Some(other) => Ok(other.to_string()),
+		None => Err(EachError::Data {
+			message: format!("Item has no {} field to pass to stdin", field),
+		}),
+	}
+}
+
+/// Stops a process which ran out of time, escalating to SIGKILL if it ignores SIGTERM. It's
+/// either been running for too long or the whole run is `past_deadline`.
+fn timed_out(process: &mut Popen, cmd_str: &str, past_deadline: bool) -> Error {
+	let stopped =
+		process.terminate().is_ok() && matches!(process.wait_timeout(KILL_GRACE), Ok(Some(_)));
+	if !stopped {
+		let _ = process.kill();
+		let _ = process.wait();
+	}
+
+	let message = match past_deadline {
+		true => format!("Command stopped at the deadline: {}", cmd_str),
+		false => format!("Command timed out: {}", cmd_str),
+	};
+	EachError::Data { message }.into()
 }
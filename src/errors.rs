@@ -1,3 +1,6 @@
+// failure_derive generates its impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 
 #[derive(Debug, Fail)]
@@ -8,6 +11,8 @@ pub enum EachError {
 	Data { message: String },
 	#[fail(display = "IO error: {:?}", inner)]
 	Io { inner: std::io::Error },
+	#[fail(display = "Interrupted")]
+	Interrupted,
 }
 
 impl From<std::io::Error> for EachError {
@@ -0,0 +1,227 @@
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use crate::errors::EachError;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+	Field(Vec<String>),
+	Literal(Value),
+	Compare(&'static str),
+	And,
+	Or,
+	Open,
+	Close,
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+	Field(Vec<String>),
+	Literal(Value),
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+	Compare(Operand, &'static str, Operand),
+	And(Box<Expr>, Box<Expr>),
+	Or(Box<Expr>, Box<Expr>),
+}
+
+/// A `--where` expression keeping items by their fields, e.g. `age > 30 && status == "active"`,
+/// for when a JMES query is more than you need.
+#[derive(Clone, Debug)]
+pub struct Filter(Expr);
+
+const COMPARISONS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+fn invalid(expr: &str, message: String) -> EachError {
+	EachError::Usage {
+		message: format!("Invalid --where expression {:?}: {}", expr, message),
+	}
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, EachError> {
+	let mut tokens = Vec::new();
+	let mut rest = expr.trim_start();
+
+	while let Some(c) = rest.chars().next() {
+		let len = if c == '"' || c == '\'' {
+			// Strings end at the first unescaped matching quote, and their escapes are JSON's.
+			let mut escaped = false;
+			let end = rest[1..].find(|ch| {
+				let end = ch == c && !escaped;
+				escaped = ch == '\\' && !escaped;
+				end
+			});
+			let end = end.ok_or_else(|| invalid(expr, "unterminated string".to_string()))? + 2;
+
+			let quoted = match c {
+				'"' => rest[..end].to_string(),
+				_ => format!(
+					"\"{}\"",
+					rest[1..end - 1].replace("\\'", "'").replace('"', "\\\"")
+				),
+			};
+			let value: String = serde_json::from_str(&quoted)
+				.map_err(|e| invalid(expr, format!("invalid string: {}", e)))?;
+			tokens.push(Token::Literal(value.into()));
+			end
+		} else if let Some(op) = COMPARISONS.iter().find(|op| rest.starts_with(*op)) {
+			tokens.push(Token::Compare(op));
+			op.len()
+		} else if rest.starts_with("&&") {
+			tokens.push(Token::And);
+			2
+		} else if rest.starts_with("||") {
+			tokens.push(Token::Or);
+			2
+		} else if c == '(' || c == ')' {
+			tokens.push(if c == '(' { Token::Open } else { Token::Close });
+			1
+		} else if c.is_ascii_digit() || c == '-' {
+			let len = rest
+				.find(|ch: char| !(ch.is_ascii_alphanumeric() || "+-.".contains(ch)))
+				.unwrap_or(rest.len());
+			let number: serde_json::Number = rest[..len]
+				.parse()
+				.map_err(|_| invalid(expr, format!("invalid number {}", &rest[..len])))?;
+			tokens.push(Token::Literal(number.into()));
+			len
+		} else if c.is_alphabetic() || c == '_' {
+			let len = rest
+				.find(|ch: char| !(ch.is_alphanumeric() || "_-.".contains(ch)))
+				.unwrap_or(rest.len());
+			tokens.push(match &rest[..len] {
+				"true" => Token::Literal(true.into()),
+				"false" => Token::Literal(false.into()),
+				"null" => Token::Literal(Value::Null),
+				path => Token::Field(path.split('.').map(str::to_string).collect()),
+			});
+			len
+		} else {
+			return Err(invalid(expr, format!("unexpected {:?}", c)));
+		};
+
+		rest = rest[len..].trim_start();
+	}
+
+	Ok(tokens)
+}
+
+struct Parser<'a> {
+	expr: &'a str,
+	tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser<'_> {
+	fn or(&mut self) -> Result<Expr, EachError> {
+		let mut filter = self.and()?;
+		while self.tokens.next_if_eq(&Token::Or).is_some() {
+			filter = Expr::Or(Box::new(filter), Box::new(self.and()?));
+		}
+
+		Ok(filter)
+	}
+
+	fn and(&mut self) -> Result<Expr, EachError> {
+		let mut filter = self.comparison()?;
+		while self.tokens.next_if_eq(&Token::And).is_some() {
+			filter = Expr::And(Box::new(filter), Box::new(self.comparison()?));
+		}
+
+		Ok(filter)
+	}
+
+	fn comparison(&mut self) -> Result<Expr, EachError> {
+		if self.tokens.next_if_eq(&Token::Open).is_some() {
+			let filter = self.or()?;
+			return match self.tokens.next() {
+				Some(Token::Close) => Ok(filter),
+				_ => Err(invalid(self.expr, "expected )".to_string())),
+			};
+		}
+
+		let left = self.operand()?;
+		let op = match self.tokens.next() {
+			Some(Token::Compare(op)) => op,
+			_ => return Err(invalid(self.expr, "expected a comparison".to_string())),
+		};
+
+		Ok(Expr::Compare(left, op, self.operand()?))
+	}
+
+	fn operand(&mut self) -> Result<Operand, EachError> {
+		match self.tokens.next() {
+			Some(Token::Field(path)) => Ok(Operand::Field(path)),
+			Some(Token::Literal(value)) => Ok(Operand::Literal(value)),
+			_ => Err(invalid(self.expr, "expected a field or value".to_string())),
+		}
+	}
+}
+
+impl Filter {
+	/// Parses comparisons of fields and JSON values with `==`, `!=`, `<`, `>`, `<=` or `>=`,
+	/// joined with `&&` and `||` and grouped with parentheses. Fields of nested objects are
+	/// separated with dots, e.g. `address.city`.
+	pub fn parse(expr: &str) -> Result<Filter, EachError> {
+		let mut parser = Parser {
+			expr,
+			tokens: tokenize(expr)?.into_iter().peekable(),
+		};
+
+		let filter = parser.or()?;
+		match parser.tokens.next() {
+			Some(token) => Err(invalid(expr, format!("unexpected {:?}", token))),
+			None => Ok(Filter(filter)),
+		}
+	}
+
+	/// Whether an item matches. Comparisons with a missing field are false, as are `<`, `>`,
+	/// `<=` and `>=` between values which aren't both numbers or both strings.
+	pub fn matches(&self, item: &Value) -> bool {
+		self.0.matches(item)
+	}
+}
+
+impl Expr {
+	fn matches(&self, item: &Value) -> bool {
+		match self {
+			Expr::And(a, b) => a.matches(item) && b.matches(item),
+			Expr::Or(a, b) => a.matches(item) || b.matches(item),
+			Expr::Compare(left, op, right) => {
+				let (left, right) = match (left.resolve(item), right.resolve(item)) {
+					(Some(left), Some(right)) => (left, right),
+					_ => return false,
+				};
+
+				let ordering = match (left, right) {
+					(Value::Number(a), Value::Number(b)) => {
+						let (a, b) = (a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+						a.partial_cmp(&b)
+					}
+					(Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+					_ => None,
+				};
+
+				match *op {
+					"==" => ordering.map_or(left == right, Ordering::is_eq),
+					"!=" => ordering.map_or(left != right, Ordering::is_ne),
+					"<" => ordering.is_some_and(Ordering::is_lt),
+					">" => ordering.is_some_and(Ordering::is_gt),
+					"<=" => ordering.is_some_and(Ordering::is_le),
+					">=" => ordering.is_some_and(Ordering::is_ge),
+					_ => unreachable!("unknown comparison {}", op),
+				}
+			}
+		}
+	}
+}
+
+impl Operand {
+	fn resolve<'a>(&'a self, item: &'a Value) -> Option<&'a Value> {
+		match self {
+			Operand::Literal(value) => Some(value),
+			Operand::Field(path) => path.iter().try_fold(item, |value, key| value.get(key)),
+		}
+	}
+}
@@ -1,22 +1,40 @@
 use clap::Arg;
-use csv::ReaderBuilder;
+use csv::{ReaderBuilder, Trim};
 use failure::Error;
 use std::collections::HashMap;
-use std::io::Read;
+use std::io::{Read, Write};
 
 use crate::errors::EachError;
 use crate::formats::Format;
 
 pub const ID: &str = "csv";
 
+/// Where cells beyond the header go in flexible mode.
+const EXTRA_KEY: &str = "_extra";
+
 #[derive(Default)]
 pub struct Csv {
+	default_delimiter: Option<u8>,
 	delimiter: Option<u8>,
 	quote: Option<u8>,
 	escape: Option<u8>,
+	comment: Option<u8>,
+	trim: bool,
+	flexible: bool,
+	case_insensitive_header: bool,
+	infer_types: bool,
+	no_headers: bool,
 }
 
 impl Csv {
+	/// A CSV variant which uses `delimiter` unless `--csv-delimiter` is given explicitly.
+	pub fn with_default_delimiter(delimiter: u8) -> Self {
+		Csv {
+			default_delimiter: Some(delimiter),
+			..Default::default()
+		}
+	}
+
 	fn reader_builder(&self) -> ReaderBuilder {
 		let mut builder = csv::ReaderBuilder::new();
 
@@ -33,6 +51,12 @@ impl Csv {
 		}
 
 		builder.escape(self.escape);
+		builder.comment(self.comment);
+		if self.trim {
+			builder.trim(Trim::All);
+		}
+
+		builder.flexible(self.flexible);
 		builder
 	}
 }
@@ -61,6 +85,56 @@ fn str_to_u8(s: &str) -> Result<u8, Error> {
 	}
 }
 
+/// Parses a cell as an integer, float or boolean if it looks like one, and empty cells as null.
+fn infer_type(cell: &str) -> serde_json::Value {
+	if cell.is_empty() {
+		return serde_json::Value::Null;
+	}
+
+	if let Ok(i) = cell.parse::<i64>() {
+		return i.into();
+	}
+
+	if let Some(f) = cell
+		.parse::<f64>()
+		.ok()
+		.and_then(serde_json::Number::from_f64)
+	{
+		return f.into();
+	}
+
+	match cell {
+		"true" => true.into(),
+		"false" => false.into(),
+		_ => cell.into(),
+	}
+}
+
+fn get_case_insensitive<'a>(
+	obj: &'a serde_json::Map<String, serde_json::Value>,
+	key: &str,
+) -> Result<Option<&'a serde_json::Value>, Error> {
+	let lower = key.to_lowercase();
+	let mut matches = obj.iter().filter(|(k, _)| k.to_lowercase() == lower);
+
+	let (found, value) = match matches.next() {
+		Some(found) => found,
+		None => return Ok(None),
+	};
+
+	if let Some((other, _)) = matches.next() {
+		return Err(EachError::Data {
+			message: format!(
+				"Ambiguous keys for column {:?}: {:?} and {:?} differ only by case",
+				key, found, other
+			),
+		}
+		.into());
+	}
+
+	Ok(Some(value))
+}
+
 const CSV_EXTS: [&str; 1] = ["csv"];
 
 impl Format for Csv {
@@ -88,10 +162,47 @@ impl Format for Csv {
 				.help("The escape character to use when parsing CSV [defaults to double quoting]")
 				.takes_value(true),
 		)
+		.arg(
+			Arg::new("csv-comment")
+				.long("csv-comment")
+				.value_name("CHAR")
+				.help("Skip lines starting with CHAR when parsing CSV")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::new("csv-trim")
+				.long("csv-trim")
+				.help("Trim whitespace around CSV headers and fields"),
+		)
+		.arg(
+			Arg::new("csv-flexible")
+				.long("csv-flexible")
+				.help("Allow CSV rows of different lengths, filling missing cells with null"),
+		)
+		.arg(
+			Arg::new("csv-no-headers")
+				.long("csv-no-headers")
+				.help("The CSV has no header row, so name the columns col0, col1 etc."),
+		)
+		.arg(
+			Arg::new("csv-infer-types")
+				.long("csv-infer-types")
+				.help("Parse numeric and boolean CSV cells as such, and empty cells as null"),
+		)
+		.arg(
+			Arg::new("csv-header-case-insensitive")
+				.long("csv-header-case-insensitive")
+				.help("Match object keys to the header case-insensitively when writing CSV"),
+		)
 	}
 
 	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
 		self.delimiter = match matches.value_of("csv-delimiter") {
+			_ if matches.occurrences_of("csv-delimiter") == 0
+				&& self.default_delimiter.is_some() =>
+			{
+				self.default_delimiter
+			}
 			Some(delimiter) => Some(str_to_u8(delimiter)?),
 			None => None,
 		};
@@ -103,6 +214,15 @@ impl Format for Csv {
 			Some(escape) => Some(str_to_u8(escape)?),
 			None => None,
 		};
+		self.comment = match matches.value_of("csv-comment") {
+			Some(comment) => Some(str_to_u8(comment)?),
+			None => None,
+		};
+		self.trim = matches.is_present("csv-trim");
+		self.flexible = matches.is_present("csv-flexible");
+		self.case_insensitive_header = matches.is_present("csv-header-case-insensitive");
+		self.infer_types = matches.is_present("csv-infer-types");
+		self.no_headers = matches.is_present("csv-no-headers");
 
 		Ok(())
 	}
@@ -114,6 +234,12 @@ impl Format for Csv {
 	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
 		let mut builder = self.reader_builder();
 
+		// Without headers, the first row is data and is checked as such.
+		if self.no_headers {
+			let mut reader = builder.from_reader(header);
+			return Ok(matches!(reader.records().next(), Some(Ok(_))));
+		}
+
 		// Let rust-csv parse the headers in this case, we don't need to preserve order just to check it's valid.
 		builder.has_headers(true);
 
@@ -127,19 +253,28 @@ impl Format for Csv {
 	}
 
 	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
-		let mut reader = self.reader_builder().from_reader(input);
-		let mut it = reader.records();
-
-		let header_row = it.next().ok_or_else(|| EachError::Data {
-			message: "Header row is empty".to_string(),
-		})?;
+		// Row lengths are checked below, so the error can say which row is wrong.
+		let mut reader = self.reader_builder().flexible(true).from_reader(input);
+		let mut it = reader.records().peekable();
+
+		let header: Vec<String> = if self.no_headers {
+			// Columns are named by position, going by the first row.
+			match it.peek() {
+				Some(Ok(first)) => (0..first.len()).map(|j| format!("col{}", j)).collect(),
+				_ => Vec::new(),
+			}
+		} else {
+			let header_row = it.next().ok_or_else(|| EachError::Data {
+				message: "Header row is empty".to_string(),
+			})?;
 
-		let header: Vec<String> = header_row?.iter().map(|s| s.into()).collect();
+			header_row?.iter().map(|s| s.into()).collect()
+		};
 
 		let mut values: Vec<serde_json::Value> = Vec::new();
 		for (i, result) in it.enumerate() {
 			let cols = result?;
-			if cols.len() != header.len() {
+			if !self.flexible && cols.len() != header.len() {
 				return Err(EachError::Data {
 					message: format!(
 						"Row {} has different number of records than the header: {:?}",
@@ -150,8 +285,28 @@ impl Format for Csv {
 			}
 
 			let mut map = serde_json::map::Map::new();
+			let mut extra: Vec<serde_json::Value> = Vec::new();
 			for (j, col) in cols.iter().enumerate() {
-				map.insert(header[j].clone(), col.to_string().into());
+				let value = match self.infer_types {
+					true => infer_type(col),
+					false => col.into(),
+				};
+
+				match header.get(j) {
+					Some(key) => {
+						map.insert(key.clone(), value);
+					}
+					None => extra.push(value),
+				}
+			}
+
+			// Only flexible rows can be short or long.
+			for key in header.iter().skip(cols.len()) {
+				map.insert(key.clone(), serde_json::Value::Null);
+			}
+
+			if !extra.is_empty() {
+				map.insert(EXTRA_KEY.to_string(), extra.into());
 			}
 
 			values.push(map.into());
@@ -160,29 +315,68 @@ impl Format for Csv {
 		Ok(values.into())
 	}
 
-	fn write(&self, values: Vec<serde_json::Value>) -> Result<(), Error> {
-		let mut writer = csv::Writer::from_writer(std::io::stdout());
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		// There's no first row to take the header from, so there's nothing to write.
+		if values.is_empty() {
+			return Ok(());
+		}
+
+		let mut builder = csv::WriterBuilder::new();
+		if let Some(delimiter) = self.delimiter {
+			builder.delimiter(delimiter);
+		}
+
+		if let Some(quote) = self.quote {
+			builder.quote(quote);
+		}
 
-		let obj = values[0].as_object().ok_or_else(|| EachError::Data {
-			message: format!("Data to write must be an object, received: {:?}", values[0]),
-		})?;
+		let mut writer = builder.from_writer(out);
 
-		let header: Vec<&String> = obj.keys().collect();
-		writer.serialize(&header)?;
+		let objs = values
+			.iter()
+			.map(|value| {
+				value.as_object().ok_or_else(|| EachError::Data {
+					message: format!("Data to write must be an object, received: {:?}", value),
+				})
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		// Rows may have different keys, so the header is every key in the order first seen.
+		let mut header: Vec<&String> = Vec::new();
+		for obj in &objs {
+			for k in obj.keys() {
+				let seen = if self.case_insensitive_header {
+					header.iter().any(|h| h.to_lowercase() == k.to_lowercase())
+				} else {
+					header.contains(&k)
+				};
+
+				if !seen {
+					header.push(k);
+				}
+			}
+		}
 
-		for value in &values {
-			let obj = match value.as_object() {
-				Some(obj) => obj,
-				None => unreachable!("The shape of each row must be the same as the header"),
-			};
+		if !self.no_headers {
+			writer.serialize(&header)?;
+		}
 
-			let row: Result<Vec<String>, _> = header
+		for obj in objs {
+			let row: Result<Vec<String>, Error> = header
 				.iter()
-				.map(|k| -> Result<String, _> {
-					let v = &obj[k.as_str()];
-					match v.as_str() {
-						Some(s) => Ok(s.to_owned()),
-						None => serde_json::to_string(v),
+				.map(|k| -> Result<String, Error> {
+					let v = if self.case_insensitive_header {
+						get_case_insensitive(obj, k)?
+					} else {
+						obj.get(k.as_str())
+					};
+
+					match v {
+						None => Ok(String::new()),
+						Some(v) => match v.as_str() {
+							Some(s) => Ok(s.to_owned()),
+							None => Ok(serde_json::to_string(v)?),
+						},
 					}
 				})
 				.collect();
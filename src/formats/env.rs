@@ -0,0 +1,159 @@
+use clap::Arg;
+use failure::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::{complete_lines, Format};
+
+pub const ID: &str = "env";
+
+#[derive(Default)]
+pub struct Env {
+	pairs: bool,
+}
+
+fn is_key(key: &str) -> bool {
+	let mut chars = key.chars();
+	matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Splits a `KEY=VALUE` line, or returns `None` for blank lines and comments.
+fn parse_line(line: &str) -> Option<Result<(String, String), String>> {
+	let line = line.trim();
+	if line.is_empty() || line.starts_with('#') {
+		return None;
+	}
+
+	let line = line.strip_prefix("export ").unwrap_or(line);
+	let (key, value) = match line.split_once('=') {
+		Some((key, value)) if is_key(key.trim()) => (key.trim(), value.trim()),
+		_ => return Some(Err(format!("Expected KEY=VALUE: {:?}", line))),
+	};
+
+	let value = if value.len() > 1 && value.starts_with('"') && value.ends_with('"') {
+		// Double quoted values may contain escapes, which are the same as JSON's.
+		serde_json::from_str(value).unwrap_or_else(|_| value[1..value.len() - 1].to_string())
+	} else if value.len() > 1 && value.starts_with('\'') && value.ends_with('\'') {
+		value[1..value.len() - 1].to_string()
+	} else {
+		value.to_string()
+	};
+
+	Some(Ok((key.to_string(), value)))
+}
+
+fn write_pair(out: &mut dyn Write, key: &str, value: &serde_json::Value) -> Result<(), Error> {
+	if !is_key(key) {
+		return Err(EachError::Data {
+			message: format!("Invalid env variable name: {:?}", key),
+		}
+		.into());
+	}
+
+	let value = match value {
+		serde_json::Value::String(s) => s.clone(),
+		serde_json::Value::Null => String::new(),
+		serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+			return Err(EachError::Data {
+				message: format!("Can't write nested value for env variable {}", key),
+			}
+			.into())
+		}
+		_ => value.to_string(),
+	};
+
+	let needs_quotes = value
+		.chars()
+		.any(|c| c.is_whitespace() || c == '#' || c == '"' || c == '\'' || c == '\\');
+	if needs_quotes {
+		writeln!(out, "{}={}", key, serde_json::to_string(&value)?)?;
+	} else {
+		writeln!(out, "{}={}", key, value)?;
+	}
+
+	Ok(())
+}
+
+const ENV_EXTS: [&str; 1] = ["env"];
+
+impl Format for Env {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args.arg(
+			Arg::new("env-pairs").long("env-pairs").help(
+				"Read and write env files as one object with key and value fields per variable",
+			),
+		)
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.pairs = matches.is_present("env-pairs");
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&ENV_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		let header = std::str::from_utf8(complete_lines(header))?;
+
+		let mut lines = header.lines().filter_map(parse_line).peekable();
+		Ok(lines.peek().is_some() && lines.all(|line| line.is_ok()))
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let mut vars = serde_json::Map::new();
+		let mut pairs: Vec<serde_json::Value> = Vec::new();
+
+		for (i, line) in BufReader::new(input).lines().enumerate() {
+			let (key, value) = match parse_line(&line?) {
+				Some(Ok(pair)) => pair,
+				Some(Err(e)) => {
+					return Err(EachError::Data {
+						message: format!("Line {}: {}", i + 1, e),
+					}
+					.into())
+				}
+				None => continue,
+			};
+
+			if self.pairs {
+				pairs.push(serde_json::json!({"key": key, "value": value}));
+			} else {
+				vars.insert(key, value.into());
+			}
+		}
+
+		Ok(match self.pairs {
+			true => pairs.into(),
+			false => vec![serde_json::Value::Object(vars)].into(),
+		})
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		for value in &values {
+			let obj = value.as_object().ok_or_else(|| EachError::Data {
+				message: format!("Env output must be objects, received: {}", value),
+			})?;
+
+			if self.pairs {
+				let key = obj.get("key").and_then(|k| k.as_str());
+				let key = key.ok_or_else(|| EachError::Data {
+					message: format!("Env pair has no string key: {}", value),
+				})?;
+				write_pair(
+					out,
+					key,
+					obj.get("value").unwrap_or(&serde_json::Value::Null),
+				)?;
+			} else {
+				for (key, value) in obj {
+					write_pair(out, key, value)?;
+				}
+			}
+		}
+
+		Ok(out.flush()?)
+	}
+}
@@ -0,0 +1,164 @@
+use clap::Arg;
+use failure::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::Range;
+
+use crate::errors::EachError;
+use crate::formats::Format;
+
+pub const ID: &str = "fixed";
+
+#[derive(Default)]
+pub struct Fixed {
+	columns: Vec<(String, Range<usize>)>,
+}
+
+fn invalid_columns(spec: &str) -> Error {
+	EachError::Usage {
+		message: format!(
+			"Invalid column {:?}, expected NAME:START-END (e.g. name:0-10)",
+			spec
+		),
+	}
+	.into()
+}
+
+fn parse_columns(spec: &str) -> Result<Vec<(String, Range<usize>)>, Error> {
+	let mut columns = Vec::new();
+	for column in spec.split(',') {
+		let (name, range) = column
+			.rsplit_once(':')
+			.ok_or_else(|| invalid_columns(column))?;
+		let (start, end) = range
+			.split_once('-')
+			.ok_or_else(|| invalid_columns(column))?;
+		let start = start
+			.trim()
+			.parse::<usize>()
+			.map_err(|_| invalid_columns(column))?;
+		let end = end
+			.trim()
+			.parse::<usize>()
+			.map_err(|_| invalid_columns(column))?;
+
+		if name.trim().is_empty() || end <= start {
+			return Err(invalid_columns(column));
+		}
+
+		columns.push((name.trim().to_string(), start..end));
+	}
+
+	Ok(columns)
+}
+
+/// Slices a line by character (not byte) offsets, so multi-byte text doesn't split a column.
+fn slice_chars(line: &str, range: &Range<usize>) -> String {
+	line.chars()
+		.skip(range.start)
+		.take(range.end - range.start)
+		.collect::<String>()
+		.trim_end()
+		.to_string()
+}
+
+const FIXED_EXTS: [&str; 0] = [];
+
+impl Format for Fixed {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args.arg(
+			Arg::new("fixed-columns")
+				.long("fixed-columns")
+				.value_name("SPEC")
+				.help("Column ranges for the fixed format, e.g. name:0-10,email:10-40")
+				.takes_value(true),
+		)
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.columns = match matches.value_of("fixed-columns") {
+			Some(spec) => parse_columns(spec)?,
+			None => Vec::new(),
+		};
+
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&FIXED_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		// Fixed-width text has no signature, so it's only guessed when columns are given.
+		Ok(!self.columns.is_empty() && std::str::from_utf8(header).is_ok())
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		if self.columns.is_empty() {
+			return Err(EachError::Usage {
+				message: "The fixed format needs --fixed-columns".to_string(),
+			}
+			.into());
+		}
+
+		let mut values: Vec<serde_json::Value> = Vec::new();
+		for line in BufReader::new(input).lines() {
+			let line = line?;
+			if line.trim().is_empty() {
+				continue;
+			}
+
+			let mut map = serde_json::Map::new();
+			for (name, range) in &self.columns {
+				map.insert(name.clone(), slice_chars(&line, range).into());
+			}
+
+			values.push(map.into());
+		}
+
+		Ok(values.into())
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		if self.columns.is_empty() {
+			return Err(EachError::Usage {
+				message: "The fixed format needs --fixed-columns".to_string(),
+			}
+			.into());
+		}
+
+		for (i, value) in values.iter().enumerate() {
+			let mut line: Vec<char> = Vec::new();
+			for (name, range) in &self.columns {
+				let field = match value.get(name) {
+					Some(serde_json::Value::String(s)) => s.clone(),
+					Some(serde_json::Value::Null) | None => String::new(),
+					Some(other) => other.to_string(),
+				};
+
+				let width = range.end - range.start;
+				if field.chars().count() > width {
+					return Err(EachError::Data {
+						message: format!(
+							"Row {} value for column {:?} is wider than {} characters: {:?}",
+							i, name, width, field
+						),
+					}
+					.into());
+				}
+
+				if line.len() < range.end {
+					line.resize(range.end, ' ');
+				}
+
+				for (j, c) in field.chars().enumerate() {
+					line[range.start + j] = c;
+				}
+			}
+
+			let line: String = line.into_iter().collect();
+			writeln!(out, "{}", line.trim_end())?;
+		}
+
+		Ok(out.flush()?)
+	}
+}
@@ -0,0 +1,113 @@
+use failure::Error;
+use ini::Ini as IniFile;
+use std::io::{Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::{complete_lines, Format};
+
+pub const ID: &str = "ini";
+
+/// The field holding the name of each item's section, null for keys before the first one.
+const SECTION_KEY: &str = "section";
+
+pub struct Ini {}
+
+const INI_EXTS: [&str; 2] = ["ini", "cfg"];
+
+/// Whether a line could be part of an INI file: a section header, a key and value, a comment
+/// or blank.
+fn is_ini_line(line: &str) -> bool {
+	let line = line.trim();
+	line.is_empty()
+		|| line.starts_with(';')
+		|| line.starts_with('#')
+		|| (line.starts_with('[') && line.ends_with(']'))
+		|| matches!(line.split_once('='), Some((key, _)) if !key.trim().is_empty())
+}
+
+impl Format for Ini {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args
+	}
+
+	fn set_arguments(&mut self, _matches: &clap::ArgMatches) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&INI_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		let header = std::str::from_utf8(complete_lines(header))?;
+		Ok(header.lines().any(|line| !line.trim().is_empty()) && header.lines().all(is_ini_line))
+	}
+
+	fn parse(&self, mut input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let file = IniFile::read_from(&mut input).map_err(|e| EachError::Data {
+			message: format!("Invalid INI: {}", e),
+		})?;
+
+		let mut sections: Vec<serde_json::Value> = Vec::new();
+		for (name, properties) in file.iter() {
+			// Keys before the first section are only an item if there are any.
+			if name.is_none() && properties.is_empty() {
+				continue;
+			}
+
+			let mut section = serde_json::Map::new();
+			let name = match name {
+				Some(name) => name.into(),
+				None => serde_json::Value::Null,
+			};
+			section.insert(SECTION_KEY.to_string(), name);
+			for (key, value) in properties.iter() {
+				section.insert(key.to_string(), value.into());
+			}
+
+			sections.push(section.into());
+		}
+
+		Ok(sections.into())
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		let mut file = IniFile::new();
+		for value in &values {
+			let obj = value.as_object().ok_or_else(|| EachError::Data {
+				message: format!("INI output must be objects, received: {}", value),
+			})?;
+
+			let name = match obj.get(SECTION_KEY) {
+				None | Some(serde_json::Value::Null) => None,
+				Some(serde_json::Value::String(name)) => Some(name.clone()),
+				Some(name) => Some(name.to_string()),
+			};
+
+			let mut section = file.with_section(name);
+			for (key, value) in obj {
+				if key == SECTION_KEY {
+					continue;
+				}
+
+				let value = match value {
+					serde_json::Value::String(s) => s.clone(),
+					serde_json::Value::Null => String::new(),
+					serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+						return Err(EachError::Data {
+							message: format!("Can't write nested value for INI key {}", key),
+						}
+						.into())
+					}
+					_ => value.to_string(),
+				};
+
+				section.set(key, value);
+			}
+		}
+
+		let mut out = out;
+		file.write_to(&mut out)?;
+		Ok(out.flush()?)
+	}
+}
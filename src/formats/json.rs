@@ -1,11 +1,16 @@
 use failure::Error;
-use std::io::Read;
+use serde::Serialize;
+use std::io::{Read, Write};
 
-use crate::formats::Format;
+use crate::formats::{indent_argument, Format};
 
 pub const ID: &str = "json";
 
-pub struct Json {}
+#[derive(Default)]
+pub struct Json {
+	indent: Option<usize>,
+	compact: bool,
+}
 
 const JSON_EXTS: [&str; 1] = ["json"];
 
@@ -14,7 +19,10 @@ impl Format for Json {
 		args
 	}
 
-	fn set_arguments(&mut self, _matches: &clap::ArgMatches) -> Result<(), Error> {
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.indent = indent_argument(matches)?;
+		self.compact = matches.is_present("compact");
+
 		Ok(())
 	}
 
@@ -23,7 +31,10 @@ impl Format for Json {
 	}
 
 	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
-		Ok(header[0] as char == '[' || header[0] as char == '{')
+		Ok(matches!(
+			header.iter().find(|b| !b.is_ascii_whitespace()),
+			Some(b'[') | Some(b'{')
+		))
 	}
 
 	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
@@ -34,7 +45,16 @@ impl Format for Json {
 		Ok(serde_json::from_str(&buffer)?)
 	}
 
-	fn write(&self, values: Vec<serde_json::Value>) -> Result<(), Error> {
-		Ok(serde_json::to_writer_pretty(std::io::stdout(), &values)?)
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		match self.indent {
+			Some(indent) => {
+				let indent = " ".repeat(indent);
+				let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+				let mut serializer = serde_json::Serializer::with_formatter(out, formatter);
+				Ok(values.serialize(&mut serializer)?)
+			}
+			None if self.compact => Ok(serde_json::to_writer(out, &values)?),
+			None => Ok(serde_json::to_writer_pretty(out, &values)?),
+		}
 	}
 }
@@ -0,0 +1,69 @@
+use failure::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::Format;
+
+pub const ID: &str = "lines";
+
+#[derive(Default)]
+pub struct Lines {
+	print0: bool,
+}
+
+const LINES_EXTS: [&str; 1] = ["txt"];
+
+impl Format for Lines {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.print0 = matches.is_present("print0");
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&LINES_EXTS
+	}
+
+	fn is_valid_header(&self, _header: &[u8]) -> Result<bool, Error> {
+		// Any text is valid, so only use this format for .txt files or when asked for.
+		Ok(false)
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let mut values: Vec<serde_json::Value> = Vec::new();
+		for line in BufReader::new(input).lines() {
+			let line = line?;
+			let line = line.strip_suffix('\r').unwrap_or(&line);
+			if line.trim().is_empty() {
+				continue;
+			}
+
+			values.push(line.into());
+		}
+
+		Ok(values.into())
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		for value in &values {
+			self.write_value(value, out)?;
+		}
+
+		Ok(out.flush()?)
+	}
+
+	fn writes_values(&self) -> bool {
+		true
+	}
+
+	fn write_value(&self, value: &serde_json::Value, out: &mut dyn Write) -> Result<(), Error> {
+		let line = value.as_str().ok_or_else(|| EachError::Data {
+			message: format!("Lines output must be strings, received: {}", value),
+		})?;
+		out.write_all(line.as_bytes())?;
+		Ok(out.write_all(if self.print0 { b"\0" } else { b"\n" })?)
+	}
+}
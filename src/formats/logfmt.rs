@@ -0,0 +1,178 @@
+use failure::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::{complete_lines, Format, Items};
+
+pub const ID: &str = "logfmt";
+
+#[derive(Default)]
+pub struct Logfmt {
+	print0: bool,
+}
+
+const LOGFMT_EXTS: [&str; 1] = ["logfmt"];
+
+fn is_key_char(c: char) -> bool {
+	!c.is_whitespace() && c != '=' && c != '"'
+}
+
+/// Splits a line into its pairs, with no value for keys given on their own.
+fn parse_line(line: &str) -> Result<Vec<(String, Option<String>)>, String> {
+	let mut pairs = Vec::new();
+	let mut rest = line.trim_start();
+
+	while !rest.is_empty() {
+		let key_len = rest.find(|c| !is_key_char(c)).unwrap_or(rest.len());
+		if key_len == 0 {
+			return Err(format!("Expected a key at {:?}", rest));
+		}
+
+		let key = &rest[..key_len];
+		rest = &rest[key_len..];
+
+		let value = match rest.strip_prefix('=') {
+			Some(value) if value.starts_with('"') => {
+				// Quoted values end at the first unescaped quote, and their escapes are JSON's.
+				let mut escaped = false;
+				let end = value[1..].find(|c| {
+					let end = c == '"' && !escaped;
+					escaped = c == '\\' && !escaped;
+					end
+				});
+				let end = end.ok_or_else(|| format!("Unterminated quoted value for {}", key))? + 2;
+
+				let quoted: String = serde_json::from_str(&value[..end])
+					.map_err(|e| format!("Invalid quoted value for {}: {}", key, e))?;
+				rest = &value[end..];
+				Some(quoted)
+			}
+			Some(value) => {
+				let end = value.find(char::is_whitespace).unwrap_or(value.len());
+				rest = &value[end..];
+				Some(value[..end].to_string())
+			}
+			None => None,
+		};
+
+		if rest.starts_with(|c: char| !c.is_whitespace()) {
+			return Err(format!("Expected a space after {}", key));
+		}
+
+		pairs.push((key.to_string(), value));
+		rest = rest.trim_start();
+	}
+
+	Ok(pairs)
+}
+
+fn to_object(pairs: Vec<(String, Option<String>)>) -> serde_json::Value {
+	pairs
+		.into_iter()
+		.map(|(key, value)| match value {
+			Some(value) => (key, value.into()),
+			None => (key, true.into()),
+		})
+		.collect::<serde_json::Map<String, serde_json::Value>>()
+		.into()
+}
+
+impl Format for Logfmt {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.print0 = matches.is_present("print0");
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&LOGFMT_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		let header = std::str::from_utf8(complete_lines(header))?;
+
+		// A lone KEY=VALUE is just as well an env file, so the first line needs a few pairs.
+		let first = header.lines().find(|line| !line.trim().is_empty());
+		Ok(match first.map(parse_line) {
+			Some(Ok(pairs)) => pairs.iter().filter(|(_, value)| value.is_some()).count() > 1,
+			_ => false,
+		})
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let values = self
+			.parse_items(input)?
+			.collect::<Result<Vec<serde_json::Value>, Error>>()?;
+		Ok(values.into())
+	}
+
+	/// Each line is parsed as it's read, so long logs needn't fit in memory.
+	fn parse_items<'a>(&self, input: &'a mut dyn Read) -> Result<Items<'a>, Error> {
+		let lines = BufReader::new(input).lines().enumerate();
+		Ok(Box::new(lines.filter_map(|(i, line)| match line {
+			Ok(line) if line.trim().is_empty() => None,
+			Ok(line) => Some(parse_line(&line).map(to_object).map_err(|e| {
+				EachError::Data {
+					message: format!("Line {}: {}", i + 1, e),
+				}
+				.into()
+			})),
+			Err(e) => Some(Err(e.into())),
+		})))
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		for value in &values {
+			self.write_value(value, out)?;
+		}
+
+		Ok(out.flush()?)
+	}
+
+	fn writes_values(&self) -> bool {
+		true
+	}
+
+	fn write_value(&self, value: &serde_json::Value, out: &mut dyn Write) -> Result<(), Error> {
+		let obj = value.as_object().ok_or_else(|| EachError::Data {
+			message: format!("Logfmt output must be objects, received: {}", value),
+		})?;
+
+		let mut pairs = Vec::with_capacity(obj.len());
+		for (key, value) in obj {
+			if key.is_empty() || !key.chars().all(is_key_char) {
+				return Err(EachError::Data {
+					message: format!("Invalid logfmt key: {:?}", key),
+				}
+				.into());
+			}
+
+			let value = match value {
+				serde_json::Value::String(s) => s.clone(),
+				serde_json::Value::Null => String::new(),
+				serde_json::Value::Array(_) | serde_json::Value::Object(_) => {
+					return Err(EachError::Data {
+						message: format!("Can't write nested value for logfmt key {}", key),
+					}
+					.into())
+				}
+				_ => value.to_string(),
+			};
+
+			let needs_quotes = value
+				.chars()
+				.any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '=' || c == '\\');
+			if needs_quotes {
+				pairs.push(format!("{}={}", key, serde_json::to_string(&value)?));
+			} else {
+				pairs.push(format!("{}={}", key, value));
+			}
+		}
+
+		out.write_all(pairs.join(" ").as_bytes())?;
+		Ok(out.write_all(if self.print0 { b"\0" } else { b"\n" })?)
+	}
+}
@@ -0,0 +1,182 @@
+use failure::Error;
+use std::io::{Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::{complete_lines, Format};
+
+pub const ID: &str = "markdown";
+
+pub struct Markdown {}
+
+/// Splits a `| a | b |` row into its trimmed cells, or returns `None` if it isn't one.
+fn split_row(line: &str) -> Option<Vec<String>> {
+	let line = line.trim();
+	let inner = line.strip_prefix('|')?.strip_suffix('|')?;
+
+	let mut cells = Vec::new();
+	let mut cell = String::new();
+	let mut chars = inner.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' => match chars.next() {
+				Some('|') => cell.push('|'),
+				Some(other) => {
+					cell.push('\\');
+					cell.push(other);
+				}
+				None => cell.push('\\'),
+			},
+			'|' => cells.push(std::mem::take(&mut cell).trim().to_string()),
+			_ => cell.push(c),
+		}
+	}
+	cells.push(cell.trim().to_string());
+
+	Some(cells)
+}
+
+fn is_separator(cells: &[String]) -> bool {
+	cells.iter().all(|cell| {
+		let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+		!dashes.is_empty() && dashes.chars().all(|c| c == '-')
+	})
+}
+
+/// Finds the first table in the input, returning its header and data rows.
+fn read_table(input: &str) -> Option<(Vec<String>, Vec<Vec<String>>)> {
+	let lines: Vec<&str> = input.lines().collect();
+
+	for i in 0..lines.len().saturating_sub(1) {
+		let header = match split_row(lines[i]) {
+			Some(header) => header,
+			None => continue,
+		};
+
+		match split_row(lines[i + 1]) {
+			Some(separator) if separator.len() == header.len() && is_separator(&separator) => {}
+			_ => continue,
+		}
+
+		let rows = lines[i + 2..]
+			.iter()
+			.map_while(|line| split_row(line))
+			.collect();
+		return Some((header, rows));
+	}
+
+	None
+}
+
+fn cell_to_string(value: Option<&serde_json::Value>) -> Result<String, Error> {
+	let s = match value {
+		Some(serde_json::Value::String(s)) => s.clone(),
+		Some(serde_json::Value::Null) | None => String::new(),
+		Some(value) => serde_json::to_string(value)?,
+	};
+
+	Ok(s.replace('|', "\\|").replace('\n', " "))
+}
+
+const MARKDOWN_EXTS: [&str; 2] = ["md", "markdown"];
+
+impl Format for Markdown {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args
+	}
+
+	fn set_arguments(&mut self, _matches: &clap::ArgMatches) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&MARKDOWN_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		let header = std::str::from_utf8(complete_lines(header))?;
+		Ok(read_table(header).is_some())
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let mut buffer = String::new();
+		input.read_to_string(&mut buffer)?;
+
+		let (header, rows) = read_table(&buffer).ok_or_else(|| EachError::Data {
+			message: "No Markdown table found".to_string(),
+		})?;
+
+		// Like GitHub, short rows are padded with empty cells and extra cells are ignored.
+		let values: Vec<serde_json::Value> = rows
+			.into_iter()
+			.map(|mut row| {
+				row.resize(header.len(), String::new());
+				header
+					.iter()
+					.cloned()
+					.zip(row.into_iter().map(serde_json::Value::from))
+					.collect()
+			})
+			.collect();
+
+		Ok(values.into())
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		let mut columns: Vec<&String> = Vec::new();
+		for value in &values {
+			let obj = value.as_object().ok_or_else(|| EachError::Data {
+				message: format!("Markdown rows must be objects, received: {}", value),
+			})?;
+
+			for key in obj.keys() {
+				if !columns.contains(&key) {
+					columns.push(key);
+				}
+			}
+		}
+
+		// Like CSV, there's no table without any columns.
+		if columns.is_empty() {
+			return Ok(());
+		}
+
+		let mut rows: Vec<Vec<String>> =
+			vec![columns.iter().map(|c| c.replace('|', "\\|")).collect()];
+		for value in &values {
+			let row: Result<Vec<String>, Error> = columns
+				.iter()
+				.map(|column| cell_to_string(value.get(column.as_str())))
+				.collect();
+			rows.push(row?);
+		}
+
+		// The separator row needs at least three dashes.
+		let widths: Vec<usize> = (0..columns.len())
+			.map(|i| {
+				rows.iter()
+					.map(|row| row[i].chars().count())
+					.max()
+					.unwrap_or(0)
+					.max(3)
+			})
+			.collect();
+
+		let separator: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+		for (i, row) in rows.iter().enumerate() {
+			let cells: Vec<String> = row
+				.iter()
+				.zip(&widths)
+				.map(|(cell, &width)| {
+					format!("{}{}", cell, " ".repeat(width - cell.chars().count()))
+				})
+				.collect();
+			writeln!(out, "| {} |", cells.join(" | "))?;
+
+			if i == 0 {
+				writeln!(out, "| {} |", separator.join(" | "))?;
+			}
+		}
+
+		Ok(out.flush()?)
+	}
+}
@@ -1,15 +1,51 @@
 mod csv;
+mod env;
+mod fixed;
+mod ini;
 mod json;
+mod lines;
+mod logfmt;
+mod markdown;
+mod msgpack;
+mod ndjson;
+mod records;
+mod sqlite;
+mod toml;
+mod tsv;
+mod xlsx;
+mod xml;
+mod yaml;
 
 use failure::Error;
 use indexmap::IndexMap;
 use std::io::prelude::*;
 
+use crate::errors::EachError;
 use crate::formats::csv::{Csv as CsvFormat, ID as CsvId};
+use crate::formats::env::{Env as EnvFormat, ID as EnvId};
+use crate::formats::fixed::{Fixed as FixedFormat, ID as FixedId};
+use crate::formats::ini::{Ini as IniFormat, ID as IniId};
 use crate::formats::json::{Json as JsonFormat, ID as JsonId};
+use crate::formats::lines::{Lines as LinesFormat, ID as LinesId};
+use crate::formats::logfmt::{Logfmt as LogfmtFormat, ID as LogfmtId};
+use crate::formats::markdown::{Markdown as MarkdownFormat, ID as MarkdownId};
+use crate::formats::msgpack::{Msgpack as MsgpackFormat, ID as MsgpackId};
+use crate::formats::ndjson::{Ndjson as NdjsonFormat, ID as NdjsonId};
+use crate::formats::records::{Records as RecordsFormat, ID as RecordsId};
+use crate::formats::sqlite::{Sqlite as SqliteFormat, ID as SqliteId};
+use crate::formats::toml::{Toml as TomlFormat, ID as TomlId};
+use crate::formats::tsv::{Tsv as TsvFormat, ID as TsvId};
+use crate::formats::xlsx::{Xlsx as XlsxFormat, ID as XlsxId};
+use crate::formats::xml::{Xml as XmlFormat, ID as XmlId};
+use crate::formats::yaml::{Yaml as YamlFormat, ID as YamlId};
 use crate::readers::{CachedReader, CACHE_LEN};
 
 pub const DEFAULT_FORMAT: &str = JsonId;
+/// The output format when `--print0` is given without one.
+pub const PRINT0_FORMAT: &str = LinesId;
+
+/// Items parsed one at a time.
+pub type Items<'a> = Box<dyn Iterator<Item = Result<serde_json::Value, Error>> + 'a>;
 
 pub trait Format {
 	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a>;
@@ -17,45 +53,174 @@ pub trait Format {
 	fn get_extensions(&self) -> &'static [&'static str];
 	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error>;
 	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error>;
-	fn write(&self, values: Vec<serde_json::Value>) -> Result<(), Error>;
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error>;
+
+	/// Whether the format can be written at all, so it's offered for output.
+	fn can_write(&self) -> bool {
+		true
+	}
+
+	/// Whether `write_value` can write values one at a time as they're produced, rather than
+	/// all of them together with `write`.
+	fn writes_values(&self) -> bool {
+		false
+	}
+
+	/// Writes a single value, for formats which `writes_values`.
+	fn write_value(&self, _value: &serde_json::Value, _out: &mut dyn Write) -> Result<(), Error> {
+		unreachable!("Only called for formats which write values one at a time")
+	}
+
+	/// Parses the items in the input as they're needed, for formats which can without reading it
+	/// all into memory first. Others parse the whole input and hand out its items.
+	fn parse_items<'a>(&self, input: &'a mut dyn Read) -> Result<Items<'a>, Error> {
+		let items = into_items(self.parse(input)?)?;
+		Ok(Box::new(items.into_iter().map(Ok)))
+	}
 }
 
+/// The items of parsed input: the elements of an array, or a lone object as the only item.
+pub fn into_items(values: serde_json::Value) -> Result<Vec<serde_json::Value>, EachError> {
+	match values {
+		serde_json::Value::Array(values) => Ok(values),
+		serde_json::Value::Object(_) => Ok(vec![values]),
+		_ => Err(EachError::Data {
+			message: format!(
+				"Input values must be an array or an object, received: {}",
+				values
+			),
+		}),
+	}
+}
+
+/// The order formats are tried in when guessing from a header, so the more lenient ones (CSV
+/// accepts most text) come later. TOML and NDJSON go before JSON since their headers can also
+/// start with a bracket, JSON before YAML since YAML accepts most JSON, and logfmt before env
+/// and INI since its lines are also `key=value`. Lines and records accept any text, so they're
+/// never guessed.
+const GUESS_ORDER: [&str; 15] = [
+	TomlId, NdjsonId, JsonId, YamlId, XmlId, MsgpackId, XlsxId, SqliteId, LogfmtId, EnvId, IniId,
+	MarkdownId, CsvId, TsvId, FixedId,
+];
+
 pub fn load_formats() -> IndexMap<&'static str, Box<dyn Format>> {
 	let mut formats: IndexMap<&'static str, Box<dyn Format>> = IndexMap::new();
-	formats.insert(JsonId, Box::new(JsonFormat {}));
+	formats.insert(TomlId, Box::new(TomlFormat {}));
+	formats.insert(NdjsonId, Box::new(NdjsonFormat::default()));
+	formats.insert(JsonId, Box::new(JsonFormat::default()));
+	formats.insert(YamlId, Box::new(YamlFormat::default()));
+	formats.insert(XmlId, Box::new(XmlFormat::default()));
+	formats.insert(MsgpackId, Box::new(MsgpackFormat {}));
+	formats.insert(XlsxId, Box::new(XlsxFormat::default()));
+	formats.insert(SqliteId, Box::new(SqliteFormat::default()));
+	formats.insert(LogfmtId, Box::new(LogfmtFormat::default()));
+	formats.insert(EnvId, Box::new(EnvFormat::default()));
+	formats.insert(IniId, Box::new(IniFormat {}));
+	formats.insert(MarkdownId, Box::new(MarkdownFormat {}));
 	formats.insert(CsvId, Box::new(CsvFormat::default()));
+	formats.insert(TsvId, Box::new(TsvFormat::default()));
+	formats.insert(FixedId, Box::new(FixedFormat::default()));
+	formats.insert(LinesId, Box::new(LinesFormat::default()));
+	formats.insert(RecordsId, Box::new(RecordsFormat::default()));
 
 	formats
 }
 
+/// Finds the ID and format for a file name by its extension.
+#[allow(clippy::borrowed_box)]
+pub fn format_for_name<'a>(
+	file_name: &str,
+	formats: &'a IndexMap<&'static str, Box<dyn Format>>,
+) -> Option<(&'static str, &'a Box<dyn Format>)> {
+	// Match the whole file name rather than its extension, so dotfiles like `.env` count.
+	formats
+		.iter()
+		.find(|(_, format)| {
+			format
+				.get_extensions()
+				.iter()
+				.any(|ext| file_name.ends_with(&format!(".{}", ext)))
+		})
+		.map(|(&id, format)| (id, format))
+}
+
 #[allow(clippy::borrowed_box)]
 pub fn guess_format<'a>(
-	ext: &Option<String>,
+	file_name: &Option<String>,
 	reader: &mut CachedReader,
 	formats: &'a IndexMap<&'static str, Box<dyn Format>>,
-) -> Option<&'a Box<dyn Format>> {
-	if let Some(ref ext) = ext {
-		for (_, format) in formats {
-			for pe in format.get_extensions() {
-				if ext == pe {
-					return Some(format);
-				}
-			}
-		}
+) -> Option<(&'static str, &'a Box<dyn Format>)> {
+	if let Some(found) = file_name
+		.as_ref()
+		.and_then(|file_name| format_for_name(file_name, formats))
+	{
+		return Some(found);
 	}
 
+	GUESS_ORDER
+		.iter()
+		.filter_map(|id| formats.get_key_value(id))
+		.find(|(_, format)| has_valid_header(reader, format.as_ref()))
+		.map(|(&id, format)| (id, format))
+}
+
+pub fn has_valid_header(reader: &mut CachedReader, format: &dyn Format) -> bool {
+	// Reads can come back short (e.g. from a pipe), so keep going until the cache is full.
 	let mut header = [0; CACHE_LEN];
-	if reader.read(&mut header).is_ok() {
-		reader.rewind();
-
-		for (_, format) in formats {
-			if let Ok(is_header) = format.is_valid_header(&header) {
-				if is_header {
-					return Some(format);
-				}
-			}
+	let mut len = 0;
+	while len < CACHE_LEN {
+		match reader.read(&mut header[len..]) {
+			Ok(0) => break,
+			Ok(read) => len += read,
+			Err(_) => return false,
 		}
 	}
 
-	None
+	if reader.rewind().is_err() {
+		return false;
+	}
+
+	matches!(format.is_valid_header(&header[..len]), Ok(true))
+}
+
+/// The extension of the format a MIME type names, for guessing the format of a response.
+pub fn content_type_extension(content_type: &str) -> Option<&'static str> {
+	let mime = content_type.split(';').next()?.trim().to_lowercase();
+	Some(match mime.as_str() {
+		"application/json" => "json",
+		"application/x-ndjson" | "application/jsonl" | "application/jsonlines" => "ndjson",
+		"application/yaml" | "application/x-yaml" | "text/yaml" => "yaml",
+		"application/toml" => "toml",
+		"application/xml" | "text/xml" => "xml",
+		"application/msgpack" | "application/x-msgpack" => "msgpack",
+		"text/csv" => "csv",
+		"text/tab-separated-values" => "tsv",
+		"text/markdown" => "md",
+		_ => return None,
+	})
+}
+
+/// Trims a header which filled the cache back to its last complete line, since it was
+/// probably cut off mid-line.
+pub fn complete_lines(header: &[u8]) -> &[u8] {
+	if header.len() < CACHE_LEN {
+		return header;
+	}
+
+	match header.iter().rposition(|&b| b == b'\n') {
+		Some(end) => &header[..end],
+		None => header,
+	}
+}
+
+/// Parses the shared `--indent` argument for formats which write nested output.
+pub fn indent_argument(matches: &clap::ArgMatches) -> Result<Option<usize>, Error> {
+	match matches.value_of("indent") {
+		Some(indent) => Ok(Some(indent.parse::<usize>().map_err(|e| {
+			EachError::Usage {
+				message: format!("Invalid indent: {} ({})", indent, e),
+			}
+		})?)),
+		None => Ok(None),
+	}
 }
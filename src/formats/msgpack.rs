@@ -0,0 +1,55 @@
+use failure::Error;
+use std::io::{Cursor, Read, Write};
+
+use crate::formats::Format;
+
+pub const ID: &str = "msgpack";
+
+pub struct Msgpack {}
+
+const MSGPACK_EXTS: [&str; 2] = ["msgpack", "mpk"];
+
+impl Format for Msgpack {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args
+	}
+
+	fn set_arguments(&mut self, _matches: &clap::ArgMatches) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&MSGPACK_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		// The array and map markers: fixarray, array 16/32, fixmap, map 16/32. The fix markers
+		// are UTF-8 continuation bytes, but the rest are also lead bytes of 2-byte characters, so
+		// the header must be invalid UTF-8 too (not just cut off mid-character) to be sure it's
+		// not text.
+		let is_marker = matches!(header.first(), Some(0x80..=0x9f) | Some(0xdc..=0xdf));
+		Ok(is_marker && matches!(std::str::from_utf8(header), Err(e) if e.error_len().is_some()))
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let mut buffer = Vec::new();
+		input.read_to_end(&mut buffer)?;
+
+		let mut cursor = Cursor::new(&buffer);
+		let mut values: Vec<serde_json::Value> = Vec::new();
+		while (cursor.position() as usize) < buffer.len() {
+			values.push(rmp_serde::from_read(&mut cursor)?);
+		}
+
+		// Like YAML documents, a stream of several values becomes an array of them.
+		Ok(match values.len() {
+			1 => values.remove(0),
+			_ => values.into(),
+		})
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		rmp_serde::encode::write_named(&mut *out, &values)?;
+		Ok(out.flush()?)
+	}
+}
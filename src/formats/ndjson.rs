@@ -0,0 +1,85 @@
+use failure::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::{complete_lines, Format, Items};
+
+pub const ID: &str = "ndjson";
+
+#[derive(Default)]
+pub struct Ndjson {
+	print0: bool,
+}
+
+const NDJSON_EXTS: [&str; 2] = ["ndjson", "jsonl"];
+
+impl Format for Ndjson {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.print0 = matches.is_present("print0");
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&NDJSON_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		let lines: Vec<&[u8]> = complete_lines(header)
+			.split(|&b| b == b'\n')
+			.filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+			.collect();
+
+		// A single line is just as well read as plain JSON, and a pretty-printed JSON
+		// document won't parse line by line.
+		Ok(lines.len() > 1
+			&& lines.iter().all(|line| {
+				matches!(
+					line.iter().find(|b| !b.is_ascii_whitespace()),
+					Some(b'{') | Some(b'[')
+				) && serde_json::from_slice::<serde_json::Value>(line).is_ok()
+			}))
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let values = self
+			.parse_items(input)?
+			.collect::<Result<Vec<serde_json::Value>, Error>>()?;
+		Ok(values.into())
+	}
+
+	/// Each line is parsed as it's read, so huge inputs needn't fit in memory.
+	fn parse_items<'a>(&self, input: &'a mut dyn Read) -> Result<Items<'a>, Error> {
+		let lines = BufReader::new(input).lines().enumerate();
+		Ok(Box::new(lines.filter_map(|(i, line)| match line {
+			Ok(line) if line.trim().is_empty() => None,
+			Ok(line) => Some(serde_json::from_str(&line).map_err(|e| {
+				EachError::Data {
+					message: format!("Line {} is not valid JSON: {}", i + 1, e),
+				}
+				.into()
+			})),
+			Err(e) => Some(Err(e.into())),
+		})))
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		for value in &values {
+			self.write_value(value, out)?;
+		}
+
+		Ok(out.flush()?)
+	}
+
+	fn writes_values(&self) -> bool {
+		true
+	}
+
+	fn write_value(&self, value: &serde_json::Value, out: &mut dyn Write) -> Result<(), Error> {
+		serde_json::to_writer(&mut *out, value)?;
+		Ok(out.write_all(if self.print0 { b"\0" } else { b"\n" })?)
+	}
+}
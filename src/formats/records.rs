@@ -0,0 +1,134 @@
+use clap::Arg;
+use failure::Error;
+use std::io::{Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::Format;
+
+pub const ID: &str = "records";
+
+pub struct Records {
+	separator: String,
+	json: bool,
+}
+
+impl Default for Records {
+	fn default() -> Self {
+		Records {
+			separator: "\n".to_string(),
+			json: false,
+		}
+	}
+}
+
+fn unescape(s: &str) -> String {
+	let mut out = String::new();
+	let mut chars = s.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			out.push(c);
+			continue;
+		}
+
+		match chars.next() {
+			Some('0') => out.push('\0'),
+			Some('n') => out.push('\n'),
+			Some('r') => out.push('\r'),
+			Some('t') => out.push('\t'),
+			Some('\\') => out.push('\\'),
+			Some(other) => {
+				out.push('\\');
+				out.push(other);
+			}
+			None => out.push('\\'),
+		}
+	}
+
+	out
+}
+
+const RECORDS_EXTS: [&str; 0] = [];
+
+impl Format for Records {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args.arg(
+			Arg::new("record-separator")
+				.long("record-separator")
+				.value_name("SEP")
+				.help("The separator between records in the records format (supports \\0, \\n, \\r, \\t escapes)")
+				.default_value("\\n")
+				.allow_hyphen_values(true)
+				.takes_value(true),
+		)
+		.arg(
+			Arg::new("record-json")
+				.long("record-json")
+				.help("Parse each record as JSON in the records format"),
+		)
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		if let Some(separator) = matches.value_of("record-separator") {
+			self.separator = unescape(separator);
+			if self.separator.is_empty() {
+				return Err(EachError::Usage {
+					message: "Record separator must not be empty".to_string(),
+				}
+				.into());
+			}
+		}
+
+		self.json = matches.is_present("record-json");
+
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&RECORDS_EXTS
+	}
+
+	fn is_valid_header(&self, _header: &[u8]) -> Result<bool, Error> {
+		// Any input can be split into records, so this format is never guessed.
+		Ok(false)
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let mut buffer = String::new();
+		input.read_to_string(&mut buffer)?;
+
+		// Input usually ends with a newline after the last separator, which isn't a record.
+		let mut chunks: Vec<&str> = buffer.split(self.separator.as_str()).collect();
+		if matches!(chunks.last(), Some(&"\n") | Some(&"\r\n")) {
+			chunks.pop();
+		}
+		while chunks.last().is_some_and(|last| last.is_empty()) {
+			chunks.pop();
+		}
+
+		let values: Result<Vec<serde_json::Value>, Error> = chunks
+			.into_iter()
+			.map(|chunk| -> Result<serde_json::Value, Error> {
+				if self.json {
+					Ok(serde_json::from_str(chunk)?)
+				} else {
+					Ok(chunk.into())
+				}
+			})
+			.collect();
+
+		Ok(values?.into())
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		for value in &values {
+			match value.as_str() {
+				Some(s) => out.write_all(s.as_bytes())?,
+				None => serde_json::to_writer(&mut *out, value)?,
+			}
+
+			out.write_all(self.separator.as_bytes())?;
+		}
+
+		Ok(out.flush()?)
+	}
+}
@@ -0,0 +1,143 @@
+use clap::Arg;
+use failure::Error;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, MAIN_DB};
+use std::io::{Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::Format;
+
+pub const ID: &str = "sqlite";
+
+#[derive(Default)]
+pub struct Sqlite {
+	table: Option<String>,
+	query: Option<String>,
+}
+
+const SQLITE_EXTS: [&str; 3] = ["sqlite", "sqlite3", "db"];
+
+/// The first 16 bytes of every SQLite database file.
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+fn to_json(value: ValueRef) -> serde_json::Value {
+	match value {
+		ValueRef::Null => serde_json::Value::Null,
+		ValueRef::Integer(i) => i.into(),
+		ValueRef::Real(f) => f.into(),
+		ValueRef::Text(text) => String::from_utf8_lossy(text).into(),
+		ValueRef::Blob(blob) => blob.to_vec().into(),
+	}
+}
+
+/// The names of the tables in the database, other than SQLite's own.
+fn table_names(conn: &Connection) -> Result<Vec<String>, Error> {
+	let mut stmt = conn.prepare(
+		"SELECT name FROM sqlite_schema WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+	)?;
+	let names = stmt.query_map([], |row| row.get(0))?;
+	Ok(names.collect::<Result<Vec<String>, _>>()?)
+}
+
+impl Sqlite {
+	/// The query selecting every row, which is from the given table or the only one there is.
+	fn query(&self, conn: &Connection) -> Result<String, Error> {
+		if let Some(ref query) = self.query {
+			return Ok(query.clone());
+		}
+
+		let table = match self.table {
+			Some(ref table) => table.clone(),
+			None => {
+				let mut tables = table_names(conn)?;
+				if tables.len() != 1 {
+					return Err(EachError::Usage {
+						message: format!(
+							"Give --sqlite-table or --sqlite-query to choose from tables: {}",
+							tables.join(", ")
+						),
+					}
+					.into());
+				}
+
+				tables.remove(0)
+			}
+		};
+
+		Ok(format!("SELECT * FROM \"{}\"", table.replace('"', "\"\"")))
+	}
+}
+
+impl Format for Sqlite {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args.arg(
+			Arg::new("sqlite-table")
+				.long("sqlite-table")
+				.value_name("NAME")
+				.help("Table to read from SQLite databases, if there's more than one")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::new("sqlite-query")
+				.long("sqlite-query")
+				.value_name("SQL")
+				.conflicts_with("sqlite-table")
+				.help("SQL query selecting the rows to read from SQLite databases")
+				.takes_value(true),
+		)
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.table = matches.value_of("sqlite-table").map(str::to_string);
+		self.query = matches.value_of("sqlite-query").map(str::to_string);
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&SQLITE_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		Ok(header.starts_with(SQLITE_MAGIC))
+	}
+
+	/// Rather than needing a path, the database is read into memory and opened there (read
+	/// only), so it can come from stdin or a URL like any other input.
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let mut buffer = Vec::new();
+		input.read_to_end(&mut buffer)?;
+
+		let mut conn = Connection::open_in_memory()?;
+		conn.deserialize_read_exact(MAIN_DB, &buffer[..], buffer.len(), true)?;
+
+		let query = self.query(&conn)?;
+		let mut stmt = conn.prepare(&query).map_err(|e| EachError::Usage {
+			message: format!("Invalid SQLite query {:?}: {}", query, e),
+		})?;
+		let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+
+		let mut values: Vec<serde_json::Value> = Vec::new();
+		let mut rows = stmt.query([])?;
+		while let Some(row) = rows.next()? {
+			let mut value = serde_json::Map::new();
+			for (i, column) in columns.iter().enumerate() {
+				value.insert(column.clone(), to_json(row.get_ref(i)?));
+			}
+
+			values.push(value.into());
+		}
+
+		Ok(values.into())
+	}
+
+	fn can_write(&self) -> bool {
+		false
+	}
+
+	fn write(&self, _values: Vec<serde_json::Value>, _out: &mut dyn Write) -> Result<(), Error> {
+		Err(EachError::Usage {
+			message: "Writing sqlite isn't supported".to_string(),
+		}
+		.into())
+	}
+}
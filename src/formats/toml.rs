@@ -0,0 +1,67 @@
+use failure::Error;
+use std::io::{Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::{complete_lines, Format};
+
+pub const ID: &str = "toml";
+
+pub struct Toml {}
+
+const TOML_EXTS: [&str; 1] = ["toml"];
+
+fn to_json(value: toml::Value) -> serde_json::Value {
+	match value {
+		toml::Value::String(s) => s.into(),
+		toml::Value::Integer(i) => i.into(),
+		toml::Value::Float(f) => f.into(),
+		toml::Value::Boolean(b) => b.into(),
+		toml::Value::Datetime(d) => d.to_string().into(),
+		toml::Value::Array(a) => a.into_iter().map(to_json).collect(),
+		toml::Value::Table(t) => t.into_iter().map(|(k, v)| (k, to_json(v))).collect(),
+	}
+}
+
+impl Format for Toml {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args
+	}
+
+	fn set_arguments(&mut self, _matches: &clap::ArgMatches) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&TOML_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		let header = complete_lines(header);
+
+		let table: toml::Table = toml::from_str(std::str::from_utf8(header)?)?;
+		Ok(!table.is_empty())
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let mut buffer = String::new();
+		input.read_to_string(&mut buffer)?;
+
+		let table: toml::Table = toml::from_str(&buffer)?;
+		Ok(to_json(toml::Value::Table(table)))
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		let table = match values.as_slice() {
+			[table @ serde_json::Value::Object(_)] => table,
+			_ => {
+				return Err(EachError::Data {
+					message: "TOML output must be a single object".to_string(),
+				}
+				.into())
+			}
+		};
+
+		write!(out, "{}", toml::to_string(table)?)?;
+		Ok(())
+	}
+}
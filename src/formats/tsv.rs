@@ -0,0 +1,49 @@
+use failure::Error;
+use std::io::{Read, Write};
+
+use crate::formats::csv::Csv;
+use crate::formats::Format;
+
+pub const ID: &str = "tsv";
+
+/// Tab separated values, read and written as CSV with a tab delimiter.
+pub struct Tsv {
+	csv: Csv,
+}
+
+impl Default for Tsv {
+	fn default() -> Self {
+		Tsv {
+			csv: Csv::with_default_delimiter(b'\t'),
+		}
+	}
+}
+
+const TSV_EXTS: [&str; 2] = ["tsv", "tab"];
+
+impl Format for Tsv {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		// The --csv-* arguments are added by the CSV format and apply here too.
+		args
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.csv.set_arguments(matches)
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&TSV_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		self.csv.is_valid_header(header)
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		self.csv.parse(input)
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		self.csv.write(values, out)
+	}
+}
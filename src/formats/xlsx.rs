@@ -0,0 +1,110 @@
+use calamine::{Data, Reader, Xlsx as XlsxFile};
+use clap::Arg;
+use failure::Error;
+use std::io::{Cursor, Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::Format;
+
+pub const ID: &str = "xlsx";
+
+#[derive(Default)]
+pub struct Xlsx {
+	sheet: Option<String>,
+}
+
+const XLSX_EXTS: [&str; 1] = ["xlsx"];
+
+/// The start of every zip archive's first local file header.
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+fn to_json(cell: &Data) -> serde_json::Value {
+	match cell {
+		Data::Empty => serde_json::Value::Null,
+		Data::Bool(b) => (*b).into(),
+		Data::Int(i) => (*i).into(),
+		// Spreadsheets store most numbers as floats, so whole ones are read as integers.
+		Data::Float(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => (*f as i64).into(),
+		Data::Float(f) => (*f).into(),
+		Data::String(s) => s.clone().into(),
+		_ => cell.to_string().into(),
+	}
+}
+
+impl Format for Xlsx {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args.arg(
+			Arg::new("xlsx-sheet")
+				.long("xlsx-sheet")
+				.value_name("NAME")
+				.help("Worksheet to read from xlsx files, instead of the first")
+				.takes_value(true),
+		)
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.sheet = matches.value_of("xlsx-sheet").map(str::to_string);
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&XLSX_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		// Other zip based documents have a [Content_Types].xml too, but only workbooks have
+		// their parts under xl/.
+		Ok(header.starts_with(ZIP_MAGIC) && header.windows(3).any(|w| w == b"xl/"))
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		// A zip archive's directory is at its end, so it has to be read whole.
+		let mut buffer = Vec::new();
+		input.read_to_end(&mut buffer)?;
+
+		let mut workbook = XlsxFile::new(Cursor::new(buffer))?;
+		let range = match self.sheet {
+			Some(ref sheet) => workbook
+				.worksheet_range(sheet)
+				.map_err(|e| EachError::Data {
+					message: format!("Unable to read worksheet {:?}: {}", sheet, e),
+				})?,
+			None => workbook
+				.worksheet_range_at(0)
+				.ok_or_else(|| EachError::Data {
+					message: "Workbook has no worksheets".to_string(),
+				})??,
+		};
+
+		let mut rows = range.rows();
+		let headers: Vec<String> = match rows.next() {
+			Some(row) => row.iter().map(|cell| cell.to_string()).collect(),
+			None => return Ok(serde_json::Value::Array(Vec::new())),
+		};
+
+		let values: Vec<serde_json::Value> = rows
+			.filter(|row| row.iter().any(|cell| *cell != Data::Empty))
+			.map(|row| {
+				headers
+					.iter()
+					.zip(row)
+					.map(|(header, cell)| (header.clone(), to_json(cell)))
+					.collect::<serde_json::Map<String, serde_json::Value>>()
+					.into()
+			})
+			.collect();
+
+		Ok(values.into())
+	}
+
+	fn can_write(&self) -> bool {
+		false
+	}
+
+	fn write(&self, _values: Vec<serde_json::Value>, _out: &mut dyn Write) -> Result<(), Error> {
+		Err(EachError::Usage {
+			message: "Writing xlsx isn't supported".to_string(),
+		}
+		.into())
+	}
+}
@@ -0,0 +1,253 @@
+use clap::Arg;
+use failure::Error;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::io::{BufReader, Read, Write};
+
+use crate::errors::EachError;
+use crate::formats::{indent_argument, Format};
+
+pub const ID: &str = "xml";
+
+const ROOT_TAG: &str = "records";
+const DEFAULT_RECORD_TAG: &str = "record";
+const DEFAULT_INDENT: usize = 2;
+
+#[derive(Default)]
+pub struct Xml {
+	record_tag: Option<String>,
+	indent: Option<usize>,
+}
+
+#[derive(Default)]
+struct Element {
+	name: String,
+	attributes: Vec<(String, String)>,
+	children: Vec<Element>,
+	text: String,
+}
+
+impl Element {
+	fn start(e: &BytesStart) -> Result<Self, Error> {
+		let mut attributes = Vec::new();
+		for attribute in e.attributes() {
+			let attribute = attribute?;
+			attributes.push((
+				String::from_utf8_lossy(attribute.key.as_ref()).to_string(),
+				quick_xml::escape::unescape(&String::from_utf8_lossy(&attribute.value))?
+					.to_string(),
+			));
+		}
+
+		Ok(Element {
+			name: String::from_utf8_lossy(e.name().as_ref()).to_string(),
+			attributes,
+			..Default::default()
+		})
+	}
+
+	/// Elements with only text become strings, anything else becomes an object with
+	/// attributes under `@name`, child elements under their tag (as an array if repeated)
+	/// and any text under `#text`.
+	fn into_json(self) -> serde_json::Value {
+		let text = self.text.trim();
+		if self.attributes.is_empty() && self.children.is_empty() {
+			return text.into();
+		}
+
+		let mut map = serde_json::Map::new();
+		for (key, value) in self.attributes {
+			map.insert(format!("@{}", key), value.into());
+		}
+
+		for child in self.children {
+			let name = child.name.clone();
+			let value = child.into_json();
+			match map.get_mut(&name) {
+				Some(serde_json::Value::Array(values)) => values.push(value),
+				Some(existing) => *existing = vec![existing.take(), value].into(),
+				None => {
+					map.insert(name, value);
+				}
+			}
+		}
+
+		if !text.is_empty() {
+			map.insert("#text".to_string(), text.into());
+		}
+
+		map.into()
+	}
+}
+
+fn read_root(input: &mut dyn Read) -> Result<Element, Error> {
+	let mut reader = Reader::from_reader(BufReader::new(input));
+	let mut buf = Vec::new();
+	let mut stack: Vec<Element> = Vec::new();
+
+	loop {
+		let element = match reader.read_event_into(&mut buf)? {
+			Event::Start(e) => {
+				stack.push(Element::start(&e)?);
+				None
+			}
+			Event::Empty(e) => Some(Element::start(&e)?),
+			Event::End(_) => stack.pop(),
+			Event::Text(e) => {
+				if let Some(parent) = stack.last_mut() {
+					parent.text.push_str(&e.unescape()?);
+				}
+				None
+			}
+			Event::CData(e) => {
+				if let Some(parent) = stack.last_mut() {
+					parent.text.push_str(&String::from_utf8_lossy(&e));
+				}
+				None
+			}
+			Event::Eof => break,
+			_ => None,
+		};
+
+		if let Some(element) = element {
+			match stack.last_mut() {
+				Some(parent) => parent.children.push(element),
+				None => return Ok(element),
+			}
+		}
+
+		buf.clear();
+	}
+
+	Err(EachError::Data {
+		message: "XML document has no root element".to_string(),
+	}
+	.into())
+}
+
+fn write_element<W: Write>(
+	writer: &mut Writer<W>,
+	name: &str,
+	value: &serde_json::Value,
+) -> Result<(), Error> {
+	let mut start = BytesStart::new(name);
+	let mut children: Vec<(&str, &serde_json::Value)> = Vec::new();
+	let mut text = None;
+
+	match value {
+		serde_json::Value::Object(map) => {
+			for (key, value) in map {
+				if let Some(attribute) = key.strip_prefix('@') {
+					start.push_attribute((attribute, scalar_to_string(value)?.as_str()));
+				} else if key == "#text" {
+					text = Some(scalar_to_string(value)?);
+				} else if let serde_json::Value::Array(values) = value {
+					children.extend(values.iter().map(|v| (key.as_str(), v)));
+				} else {
+					children.push((key.as_str(), value));
+				}
+			}
+		}
+		serde_json::Value::Null => {}
+		_ => text = Some(scalar_to_string(value)?),
+	}
+
+	if children.is_empty() && text.is_none() {
+		writer.write_event(Event::Empty(start))?;
+		return Ok(());
+	}
+
+	writer.write_event(Event::Start(start))?;
+	if let Some(text) = text {
+		writer.write_event(Event::Text(BytesText::new(&text)))?;
+	}
+
+	for (name, child) in children {
+		write_element(writer, name, child)?;
+	}
+
+	writer.write_event(Event::End(BytesEnd::new(name)))?;
+	Ok(())
+}
+
+fn scalar_to_string(value: &serde_json::Value) -> Result<String, Error> {
+	match value {
+		serde_json::Value::String(s) => Ok(s.clone()),
+		serde_json::Value::Array(_) | serde_json::Value::Object(_) => Err(EachError::Data {
+			message: format!("Can't write nested value as XML text: {}", value),
+		}
+		.into()),
+		_ => Ok(value.to_string()),
+	}
+}
+
+const XML_EXTS: [&str; 1] = ["xml"];
+
+impl Format for Xml {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args.arg(
+			Arg::new("xml-record-tag")
+				.long("xml-record-tag")
+				.value_name("TAG")
+				.help("The repeating XML element to read each item from [defaults to the first child of the root]")
+				.takes_value(true),
+		)
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.record_tag = matches.value_of("xml-record-tag").map(|s| s.to_string());
+		self.indent = indent_argument(matches)?;
+
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&XML_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		let start = header.iter().position(|b| !b.is_ascii_whitespace());
+		Ok(match start.map(|i| &header[i..]) {
+			Some(rest) => {
+				rest.starts_with(b"<?xml")
+					|| (rest.len() > 1 && rest[0] == b'<' && rest[1].is_ascii_alphabetic())
+			}
+			None => false,
+		})
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		let root = read_root(input)?;
+
+		let record_tag = match self.record_tag {
+			Some(ref record_tag) => record_tag.clone(),
+			None => match root.children.first() {
+				Some(child) => child.name.clone(),
+				None => return Ok(serde_json::Value::Array(Vec::new())),
+			},
+		};
+
+		Ok(root
+			.children
+			.into_iter()
+			.filter(|child| child.name == record_tag)
+			.map(Element::into_json)
+			.collect())
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		let mut writer = Writer::new_with_indent(out, b' ', self.indent.unwrap_or(DEFAULT_INDENT));
+
+		let record_tag = self.record_tag.as_deref().unwrap_or(DEFAULT_RECORD_TAG);
+
+		writer.write_event(Event::Start(BytesStart::new(ROOT_TAG)))?;
+		for value in &values {
+			write_element(&mut writer, record_tag, value)?;
+		}
+		writer.write_event(Event::End(BytesEnd::new(ROOT_TAG)))?;
+
+		let out = writer.into_inner();
+		out.write_all(b"\n")?;
+		Ok(out.flush()?)
+	}
+}
@@ -0,0 +1,153 @@
+use failure::Error;
+use serde::Deserialize;
+use std::io::{Read, Write};
+
+use crate::formats::{complete_lines, indent_argument, Format};
+
+pub const ID: &str = "yaml";
+
+#[derive(Default)]
+pub struct Yaml {
+	indent: Option<usize>,
+}
+
+const YAML_EXTS: [&str; 2] = ["yaml", "yml"];
+
+fn parse_documents(deserializer: serde_yaml::Deserializer) -> Result<serde_json::Value, Error> {
+	let mut documents: Vec<serde_json::Value> = Vec::new();
+	for document in deserializer {
+		documents.push(serde_json::Value::deserialize(document)?);
+	}
+
+	// A stream of several documents becomes an array of them, so each document is an item.
+	Ok(match documents.len() {
+		1 => documents.remove(0),
+		_ => documents.into(),
+	})
+}
+
+impl Format for Yaml {
+	fn add_arguments<'a>(&self, args: clap::Command<'a>) -> clap::Command<'a> {
+		args
+	}
+
+	fn set_arguments(&mut self, matches: &clap::ArgMatches) -> Result<(), Error> {
+		self.indent = indent_argument(matches)?;
+
+		Ok(())
+	}
+
+	fn get_extensions(&self) -> &'static [&'static str] {
+		&YAML_EXTS
+	}
+
+	fn is_valid_header(&self, header: &[u8]) -> Result<bool, Error> {
+		let header = complete_lines(header);
+
+		// Almost any text is a valid YAML scalar, so require a mapping or sequence.
+		let mut documents = serde_yaml::Deserializer::from_slice(header);
+		Ok(matches!(
+			documents.next().map(serde_json::Value::deserialize),
+			Some(Ok(serde_json::Value::Object(_))) | Some(Ok(serde_json::Value::Array(_)))
+		))
+	}
+
+	fn parse(&self, input: &mut dyn Read) -> Result<serde_json::Value, Error> {
+		parse_documents(serde_yaml::Deserializer::from_reader(input))
+	}
+
+	fn write(&self, values: Vec<serde_json::Value>, out: &mut dyn Write) -> Result<(), Error> {
+		match self.indent {
+			// Nested YAML has to be indented by something.
+			Some(indent) => {
+				let mut yaml = String::new();
+				write_block(&values.into(), indent.max(1), 0, &mut yaml)?;
+				Ok(out.write_all(yaml.as_bytes())?)
+			}
+			None => Ok(serde_yaml::to_writer(out, &values)?),
+		}
+	}
+}
+
+/// A scalar, or empty mapping or sequence, on one line. serde_yaml quotes it wherever it's
+/// needed, but writes multi-line strings as block scalars, so those are written as JSON, which
+/// is also a valid (double quoted) YAML scalar.
+fn write_scalar(value: &serde_json::Value) -> Result<String, Error> {
+	let yaml = serde_yaml::to_string(value)?;
+	match yaml.trim_end() {
+		line if line.contains('\n') => Ok(serde_json::to_string(value)?),
+		line => Ok(line.to_string()),
+	}
+}
+
+/// Whether a value is written on the lines after its key or `-`, rather than the same line.
+fn is_block(value: &serde_json::Value) -> bool {
+	match value {
+		serde_json::Value::Object(map) => !map.is_empty(),
+		serde_json::Value::Array(values) => !values.is_empty(),
+		_ => false,
+	}
+}
+
+/// Writes a value in block style with each nested `level` indented by `indent` spaces, which
+/// serde_yaml can't be told to do.
+fn write_block(
+	value: &serde_json::Value,
+	indent: usize,
+	level: usize,
+	out: &mut String,
+) -> Result<(), Error> {
+	let pad = " ".repeat(indent * level);
+	match value {
+		serde_json::Value::Object(map) if is_block(value) => {
+			for (key, value) in map {
+				out.push_str(&pad);
+				out.push_str(&write_scalar(&key.as_str().into())?);
+				out.push(':');
+				match is_block(value) {
+					true => {
+						out.push('\n');
+						write_block(value, indent, level + 1, out)?;
+					}
+					false => {
+						out.push(' ');
+						out.push_str(&write_scalar(value)?);
+						out.push('\n');
+					}
+				}
+			}
+		}
+		serde_json::Value::Array(values) if is_block(value) => {
+			for value in values {
+				out.push_str(&pad);
+				out.push('-');
+				match is_block(value) {
+					// The first line of a nested block goes after the `-`, padded so the rest of
+					// its lines line up with it.
+					true if indent > 1 => {
+						let mut nested = String::new();
+						write_block(value, indent, level + 1, &mut nested)?;
+						out.push_str(&" ".repeat(indent - 1));
+						out.push_str(nested.trim_start_matches(' '));
+					}
+					true => {
+						out.push('\n');
+						write_block(value, indent, level + 1, out)?;
+					}
+					false => {
+						out.push(' ');
+						out.push_str(&write_scalar(value)?);
+						out.push('\n');
+					}
+				}
+			}
+		}
+		_ => {
+			out.push_str(&pad);
+			out.push_str(&write_scalar(value)?);
+			out.push('\n');
+		}
+	}
+
+	Ok(())
+}
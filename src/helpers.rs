@@ -0,0 +1,402 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, FixedOffset, Utc};
+use handlebars::{
+	Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Renders its argument as JSON, e.g. `{{json address}}`.
+struct Json {
+	pretty: bool,
+}
+
+impl HelperDef for Json {
+	fn call<'reg: 'rc, 'rc>(
+		&self,
+		h: &Helper<'reg, 'rc>,
+		_: &'reg Handlebars<'reg>,
+		_: &'rc Context,
+		_: &mut RenderContext<'reg, 'rc>,
+		out: &mut dyn Output,
+	) -> HelperResult {
+		let param = h
+			.param(0)
+			.ok_or_else(|| RenderError::new(format!("{} needs a value", h.name())))?;
+
+		let json = match self.pretty {
+			true => serde_json::to_string_pretty(param.value()),
+			false => serde_json::to_string(param.value()),
+		}
+		.map_err(|e| RenderError::from_error(h.name(), e))?;
+
+		out.write(&json)?;
+		Ok(())
+	}
+}
+
+/// Renders the string form of its argument converted by a function, e.g. `{{upper name}}`.
+struct Convert {
+	convert: fn(&str) -> String,
+}
+
+impl HelperDef for Convert {
+	fn call<'reg: 'rc, 'rc>(
+		&self,
+		h: &Helper<'reg, 'rc>,
+		_: &'reg Handlebars<'reg>,
+		_: &'rc Context,
+		_: &mut RenderContext<'reg, 'rc>,
+		out: &mut dyn Output,
+	) -> HelperResult {
+		let param = h
+			.param(0)
+			.ok_or_else(|| RenderError::new(format!("{} needs a value", h.name())))?;
+
+		out.write(&(self.convert)(&stringify(h, param.value())?))?;
+		Ok(())
+	}
+}
+
+/// Decodes the string form of its argument from base64, e.g. `{{base64decode payload}}`. It's an
+/// error if that isn't valid base64, or doesn't decode to UTF-8 text.
+struct Base64Decode;
+
+impl HelperDef for Base64Decode {
+	fn call<'reg: 'rc, 'rc>(
+		&self,
+		h: &Helper<'reg, 'rc>,
+		_: &'reg Handlebars<'reg>,
+		_: &'rc Context,
+		_: &mut RenderContext<'reg, 'rc>,
+		out: &mut dyn Output,
+	) -> HelperResult {
+		let param = h
+			.param(0)
+			.ok_or_else(|| RenderError::new(format!("{} needs a value", h.name())))?;
+
+		let decoded = BASE64
+			.decode(stringify(h, param.value())?.trim())
+			.map_err(|e| RenderError::from_error(h.name(), e))?;
+		let decoded =
+			String::from_utf8(decoded).map_err(|e| RenderError::from_error(h.name(), e))?;
+
+		out.write(&decoded)?;
+		Ok(())
+	}
+}
+
+/// Formats a timestamp given as epoch seconds or an RFC 3339 string with a strftime format,
+/// e.g. `{{date created "%Y-%m-%d"}}`. Epoch times are in UTC, and RFC 3339 ones keep their
+/// offset.
+struct Date;
+
+impl HelperDef for Date {
+	fn call<'reg: 'rc, 'rc>(
+		&self,
+		h: &Helper<'reg, 'rc>,
+		_: &'reg Handlebars<'reg>,
+		_: &'rc Context,
+		_: &mut RenderContext<'reg, 'rc>,
+		out: &mut dyn Output,
+	) -> HelperResult {
+		let (value, format) = match (h.param(0), h.param(1).and_then(|p| p.value().as_str())) {
+			(Some(value), Some(format)) => (value.value(), format),
+			_ => return Err(RenderError::new("date needs a time and a format string")),
+		};
+
+		let invalid = || {
+			RenderError::new(format!(
+				"date needs epoch seconds or an RFC 3339 time, received: {}",
+				value
+			))
+		};
+		let epoch = match value {
+			serde_json::Value::Number(n) => n.as_f64(),
+			serde_json::Value::String(s) => s.trim().parse::<f64>().ok(),
+			_ => return Err(invalid()),
+		};
+
+		let time: DateTime<FixedOffset> = match (epoch, value.as_str()) {
+			(Some(epoch), _) => {
+				// The fraction is always counted forward from the whole second before, even when
+				// the time's before the epoch, and may round up to the next one.
+				let nanos = (epoch.rem_euclid(1.0) * 1e9).round() as i64;
+				let secs = epoch.div_euclid(1.0) as i64 + nanos / 1_000_000_000;
+				DateTime::<Utc>::from_timestamp(secs, (nanos % 1_000_000_000) as u32)
+					.ok_or_else(invalid)?
+					.into()
+			}
+			(None, Some(s)) => DateTime::parse_from_rfc3339(s.trim()).map_err(|_| invalid())?,
+			(None, None) => return Err(invalid()),
+		};
+
+		// Formatting with an invalid specifier fails rather than panicking this way.
+		let mut formatted = String::new();
+		std::fmt::Write::write_fmt(&mut formatted, format_args!("{}", time.format(format)))
+			.map_err(|_| RenderError::new(format!("Invalid date format: {:?}", format)))?;
+
+		out.write(&formatted)?;
+		Ok(())
+	}
+}
+
+/// A number, or a string holding one as CSV fields do.
+enum Number {
+	Int(i64),
+	Float(f64),
+}
+
+impl Number {
+	fn from_value(h: &Helper, value: &serde_json::Value) -> Result<Number, RenderError> {
+		let number = match value {
+			serde_json::Value::Number(n) => n
+				.as_i64()
+				.map(Number::Int)
+				.or(n.as_f64().map(Number::Float)),
+			serde_json::Value::String(s) => {
+				let s = s.trim();
+				s.parse()
+					.map(Number::Int)
+					.ok()
+					.or(s.parse().map(Number::Float).ok())
+			}
+			_ => None,
+		};
+
+		number.ok_or_else(|| {
+			RenderError::new(format!("{} needs numbers, received: {}", h.name(), value))
+		})
+	}
+
+	fn as_f64(&self) -> f64 {
+		match *self {
+			Number::Int(i) => i as f64,
+			Number::Float(f) => f,
+		}
+	}
+}
+
+/// Renders the result of arithmetic on two numbers, e.g. `{{add offset 1}}`. Integers stay
+/// integers unless the result can't be one, e.g. `{{div 7 2}}` is 3.5, and it's an error for
+/// them to overflow rather than losing precision as a float.
+struct Arithmetic {
+	/// The result for two integers, or None if it overflows.
+	ints: fn(i64, i64) -> Option<i64>,
+	floats: fn(f64, f64) -> f64,
+	divides: bool,
+	/// Whether integers which don't divide exactly give a fraction.
+	fractional: bool,
+}
+
+impl HelperDef for Arithmetic {
+	fn call<'reg: 'rc, 'rc>(
+		&self,
+		h: &Helper<'reg, 'rc>,
+		_: &'reg Handlebars<'reg>,
+		_: &'rc Context,
+		_: &mut RenderContext<'reg, 'rc>,
+		out: &mut dyn Output,
+	) -> HelperResult {
+		let (a, b) = match (h.param(0), h.param(1)) {
+			(Some(a), Some(b)) => (
+				Number::from_value(h, a.value())?,
+				Number::from_value(h, b.value())?,
+			),
+			_ => return Err(RenderError::new(format!("{} needs two numbers", h.name()))),
+		};
+
+		if self.divides && b.as_f64() == 0.0 {
+			return Err(RenderError::new(format!("{} by zero", h.name())));
+		}
+
+		let ints = match (&a, &b) {
+			(Number::Int(a), Number::Int(b)) if !self.fractional || a.wrapping_rem(*b) == 0 => {
+				let result = (self.ints)(*a, *b).ok_or_else(|| {
+					RenderError::new(format!("{} overflowed: {} and {}", h.name(), a, b))
+				})?;
+				Some(result)
+			}
+			_ => None,
+		};
+
+		let result: serde_json::Value = match ints {
+			Some(result) => result.into(),
+			None => {
+				let result = (self.floats)(a.as_f64(), b.as_f64());
+				serde_json::Number::from_f64(result)
+					.ok_or_else(|| RenderError::new(format!("{} result isn't a number", h.name())))?
+					.into()
+			}
+		};
+
+		out.write(&result.to_string())?;
+		Ok(())
+	}
+}
+
+/// Renders its first argument, or the second if the first is missing, null or empty, e.g.
+/// `{{default dir "."}}`.
+struct DefaultValue;
+
+impl HelperDef for DefaultValue {
+	fn call<'reg: 'rc, 'rc>(
+		&self,
+		h: &Helper<'reg, 'rc>,
+		_: &'reg Handlebars<'reg>,
+		_: &'rc Context,
+		_: &mut RenderContext<'reg, 'rc>,
+		out: &mut dyn Output,
+	) -> HelperResult {
+		let fallback = h
+			.param(1)
+			.ok_or_else(|| RenderError::new("default needs a value and a fallback"))?;
+
+		// A missing field renders as null.
+		let value = match h.param(0).map(|p| p.value()) {
+			None | Some(serde_json::Value::Null) => fallback.value(),
+			Some(serde_json::Value::String(s)) if s.is_empty() => fallback.value(),
+			Some(value) => value,
+		};
+
+		out.write(&stringify(h, value)?)?;
+		Ok(())
+	}
+}
+
+/// Strings as they are, anything else as JSON.
+fn stringify(h: &Helper, value: &serde_json::Value) -> Result<String, RenderError> {
+	match value.as_str() {
+		Some(s) => Ok(s.to_owned()),
+		None => serde_json::to_string(value).map_err(|e| RenderError::from_error(h.name(), e)),
+	}
+}
+
+/// Upper cases the first letter of each word and lower cases the rest.
+fn title_case(s: &str) -> String {
+	let mut title = String::with_capacity(s.len());
+	let mut word_start = true;
+	for c in s.chars() {
+		if word_start {
+			title.extend(c.to_uppercase());
+		} else {
+			title.extend(c.to_lowercase());
+		}
+
+		word_start = c.is_whitespace();
+	}
+
+	title
+}
+
+/// Everything but the characters RFC 3986 leaves unreserved.
+const URL_RESERVED: &AsciiSet = &NON_ALPHANUMERIC
+	.remove(b'-')
+	.remove(b'.')
+	.remove(b'_')
+	.remove(b'~');
+
+fn url_encode(s: &str) -> String {
+	utf8_percent_encode(s, URL_RESERVED).to_string()
+}
+
+fn base64_encode(s: &str) -> String {
+	BASE64.encode(s)
+}
+
+/// Wraps in single quotes, closing them around any single quotes inside, so a POSIX shell
+/// reads it as one word.
+fn shell_quote(s: &str) -> String {
+	format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Adds our helpers to the registry the command templates are rendered with.
+pub fn register(templates: &mut Handlebars) {
+	templates.register_helper("json", Box::new(Json { pretty: false }));
+	templates.register_helper("json-pretty", Box::new(Json { pretty: true }));
+	templates.register_helper("default", Box::new(DefaultValue));
+	templates.register_helper(
+		"upper",
+		Box::new(Convert {
+			convert: str::to_uppercase,
+		}),
+	);
+	templates.register_helper(
+		"lower",
+		Box::new(Convert {
+			convert: str::to_lowercase,
+		}),
+	);
+	templates.register_helper(
+		"title",
+		Box::new(Convert {
+			convert: title_case,
+		}),
+	);
+	templates.register_helper(
+		"urlencode",
+		Box::new(Convert {
+			convert: url_encode,
+		}),
+	);
+	templates.register_helper(
+		"shquote",
+		Box::new(Convert {
+			convert: shell_quote,
+		}),
+	);
+	templates.register_helper(
+		"base64encode",
+		Box::new(Convert {
+			convert: base64_encode,
+		}),
+	);
+	templates.register_helper("base64decode", Box::new(Base64Decode));
+	templates.register_helper("date", Box::new(Date));
+	templates.register_helper(
+		"add",
+		Box::new(Arithmetic {
+			ints: i64::checked_add,
+			floats: |a, b| a + b,
+			divides: false,
+			fractional: false,
+		}),
+	);
+	templates.register_helper(
+		"sub",
+		Box::new(Arithmetic {
+			ints: i64::checked_sub,
+			floats: |a, b| a - b,
+			divides: false,
+			fractional: false,
+		}),
+	);
+	templates.register_helper(
+		"mul",
+		Box::new(Arithmetic {
+			ints: i64::checked_mul,
+			floats: |a, b| a * b,
+			divides: false,
+			fractional: false,
+		}),
+	);
+	templates.register_helper(
+		"div",
+		Box::new(Arithmetic {
+			ints: i64::checked_div,
+			floats: |a, b| a / b,
+			divides: true,
+			fractional: true,
+		}),
+	);
+	templates.register_helper(
+		"mod",
+		Box::new(Arithmetic {
+			// The only remainder which overflows, i64::MIN % -1, is 0 anyway.
+			ints: |a, b| Some(a.wrapping_rem(b)),
+			floats: |a, b| a % b,
+			divides: true,
+			fractional: false,
+		}),
+	);
+}
@@ -0,0 +1,1088 @@
+//! Parses structured data in any of several formats and runs a templated command for each item.
+//! This is the library behind the `each` command line tool, for embedding it in other programs.
+//!
+//! ```no_run
+//! use each::{Action, Each};
+//!
+//! let mut each = Each::new();
+//! each.add_query("[?age > `30`]")?;
+//!
+//! let mut action = Action::new(
+//!     "echo".to_string(),
+//!     None,
+//!     vec!["{{name}}".to_string()],
+//!     Vec::new(),
+//!     None,
+//!     false,
+//!     false,
+//! )
+//! .unwrap();
+//! action.collect = true;
+//! each.action = Some(action);
+//!
+//! let file = std::fs::File::open("people.csv")?;
+//! let values = each.parse_input(Some("people.csv"), None, Box::new(file))?;
+//! let names = each.run(&values)?;
+//! # Ok::<(), each::EachError>(())
+//! ```
+//!
+//! Inputs can also be added to be read as they're needed, with the items deduped, sorted,
+//! skipped and limited and the output written as the command line tool does.
+//!
+//! ```no_run
+//! use each::{Each, Sort};
+//!
+//! let mut each = Each::new();
+//! each.add_input("people.csv", None, false)?;
+//! each.sort = Some(Sort {
+//!     field: "age".to_string(),
+//!     desc: true,
+//! });
+//! each.limit = Some(10);
+//! each.output_format = "csv".to_string();
+//! each.run_inputs()?;
+//! # Ok::<(), each::EachError>(())
+//! ```
+
+pub mod action;
+pub mod errors;
+pub mod filter;
+pub mod formats;
+pub mod helpers;
+pub mod readers;
+pub mod writers;
+
+use dialoguer::theme::{ColorfulTheme, SimpleTheme, Theme};
+use dialoguer::Confirm;
+use handlebars::Handlebars;
+use indexmap::IndexMap;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::debug;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use walkdir::WalkDir;
+
+pub use action::{convert_delimiters, Action, PreparedCommand};
+pub use errors::EachError;
+pub use filter::Filter;
+pub use formats::Format;
+use readers::{CachedReader, FileReader, UrlReader};
+
+/// Items read from an input, one at a time.
+pub type Items<'a> = Box<dyn Iterator<Item = Result<serde_json::Value, EachError>> + 'a>;
+
+/// Counts of processed items, for the --keep-going and --stats summaries.
+#[derive(Default)]
+pub struct Summary {
+	pub items: AtomicUsize,
+	pub ran: AtomicUsize,
+	/// Commands which ran successfully, so not those only printed by a dry run or declined.
+	pub succeeded: AtomicUsize,
+	pub failed: AtomicUsize,
+}
+
+/// Expands each item with an array in `field` into one item per element, for `--flatten`.
+#[derive(Clone)]
+pub struct Flatten {
+	pub field: String,
+	/// Whether to drop items without the array, rather than keeping them as they are.
+	pub drop_missing: bool,
+}
+
+impl Flatten {
+	/// The item with the array replaced by each element in turn. Objects are merged into the
+	/// item, and anything else is put under `value`.
+	pub fn apply(&self, item: serde_json::Value) -> Vec<serde_json::Value> {
+		let elements = match item.get(&self.field) {
+			Some(serde_json::Value::Array(elements)) => elements.clone(),
+			_ if self.drop_missing => return Vec::new(),
+			_ => return vec![item],
+		};
+
+		let rest: serde_json::Map<String, serde_json::Value> = match item {
+			serde_json::Value::Object(obj) => {
+				obj.into_iter().filter(|(k, _)| *k != self.field).collect()
+			}
+			_ => unreachable!("only objects have fields"),
+		};
+
+		elements
+			.into_iter()
+			.map(|element| {
+				let mut expanded = rest.clone();
+				match element {
+					serde_json::Value::Object(element) => expanded.extend(element),
+					element => {
+						expanded.insert("value".to_string(), element);
+					}
+				}
+				expanded.into()
+			})
+			.collect()
+	}
+}
+
+/// Orders items by a field, for `--sort`. The sort is stable, so items which compare the same
+/// stay in input order either way.
+#[derive(Clone)]
+pub struct Sort {
+	pub field: String,
+	/// Whether the highest values come first.
+	pub desc: bool,
+}
+
+/// Drops items which are the same as an earlier one, for `--unique` and `--unique-by`.
+#[derive(Clone, Default)]
+pub struct Unique {
+	/// Compares only this field, rather than the whole item.
+	pub field: Option<String>,
+}
+
+/// An input added to read items from.
+struct Input {
+	file_name: Option<String>,
+	format_id: Option<String>,
+	reader: CachedReader,
+}
+
+/// The formats input is read with, the queries applied to it and the command run for each item.
+pub struct Each<'a> {
+	pub formats: IndexMap<&'static str, Box<dyn Format>>,
+	/// Queries applied in turn, each only to inputs of its format if it has one.
+	pub queries: Vec<(Option<&'static str>, jmespath::Expression<'static>)>,
+	/// String fields parsed in another format, given as the field and the format's ID, after
+	/// the queries.
+	pub parse_fields: Vec<(String, &'static str)>,
+	/// Expands items' arrays into separate items, after the queries and parsing fields.
+	pub flatten: Option<Flatten>,
+	/// Filters every item must match, applied after the queries and flattening.
+	pub filters: Vec<Filter>,
+	/// Errors if an input with a format given isn't valid in it, rather than parsing what it can.
+	pub strict_format: bool,
+	/// Drops items which are the same as an earlier one, before sorting.
+	pub unique: Option<Unique>,
+	pub sort: Option<Sort>,
+	/// How many items to skip before processing any, after the filters, deduping and sorting.
+	pub skip: usize,
+	/// The most items to process, after skipping.
+	pub limit: Option<usize>,
+	pub action: Option<Action<'a>>,
+	/// The ID of the format output is written in.
+	pub output_format: String,
+	/// The file output is written to rather than stdout, compressed if it's named `.gz` or
+	/// `.zst`.
+	pub output: Option<PathBuf>,
+	/// Without a command, each item is written to the file this template renders instead.
+	pub output_template: Option<String>,
+	/// Lets items whose output template renders the same file share it.
+	pub output_share: bool,
+	/// Adds to the end of output files which already exist, rather than replacing them.
+	pub append: bool,
+	/// Prints how many items were processed, and how, to stderr once they have been.
+	pub stats: bool,
+	pub summary: Summary,
+	inputs: Vec<Input>,
+}
+
+impl Default for Each<'_> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<'a> Each<'a> {
+	/// Every format with its default settings, and no queries or command.
+	pub fn new() -> Self {
+		Self::with_formats(formats::load_formats())
+	}
+
+	pub fn with_formats(formats: IndexMap<&'static str, Box<dyn Format>>) -> Self {
+		Each {
+			formats,
+			queries: Vec::new(),
+			parse_fields: Vec::new(),
+			flatten: None,
+			filters: Vec::new(),
+			strict_format: false,
+			unique: None,
+			sort: None,
+			skip: 0,
+			limit: None,
+			action: None,
+			output_format: formats::DEFAULT_FORMAT.to_string(),
+			output: None,
+			output_template: None,
+			output_share: false,
+			append: false,
+			stats: false,
+			summary: Summary::default(),
+			inputs: Vec::new(),
+		}
+	}
+
+	/// Adds an input to read items from: a file, a URL, a glob pattern matching files, or a
+	/// directory, whose files with a known format are read (and those in its subdirectories too
+	/// if `recursive` is set). The format is guessed unless one is given.
+	pub fn add_input(
+		&mut self,
+		input: &str,
+		format_id: Option<&str>,
+		recursive: bool,
+	) -> Result<(), EachError> {
+		for input_path in expand_input(input, recursive, &self.formats)? {
+			if input_path.starts_with("http://") || input_path.starts_with("https://") {
+				let reader = UrlReader::new(&input_path).map_err(|e| EachError::Data {
+					message: format!("Couldn't fetch {}: {}", &input_path, e),
+				})?;
+
+				// Prefer the extension in the URL, falling back on the response's content type.
+				let file_name = UrlReader::file_name(&input_path).or_else(|| {
+					formats::content_type_extension(&reader.content_type)
+						.map(|ext| format!("response.{}", ext))
+				});
+
+				self.inputs.push(Input {
+					file_name,
+					format_id: format_id.map(str::to_string),
+					reader: readers::input_reader(Box::new(reader)),
+				});
+				continue;
+			}
+
+			// Gzipped files are decompressed, so the format is that of the name inside.
+			let file_name = Path::new(&input_path).file_name().map(|name| {
+				let name = name.to_string_lossy();
+				name.strip_suffix(".gz").unwrap_or(&name).to_string()
+			});
+			let reader = FileReader::new(&input_path).map_err(|e| EachError::Data {
+				message: format!("Couldn't open file {}: {}", &input_path, e),
+			})?;
+
+			self.inputs.push(Input {
+				file_name,
+				format_id: format_id.map(str::to_string),
+				reader: readers::input_reader(Box::new(reader)),
+			});
+		}
+
+		Ok(())
+	}
+
+	/// Adds an input read from `reader`, e.g. stdin, whose format is guessed from `file_name` or
+	/// what it starts with unless one is given.
+	pub fn add_reader(
+		&mut self,
+		file_name: Option<&str>,
+		format_id: Option<&str>,
+		reader: Box<dyn Read>,
+	) {
+		self.inputs.push(Input {
+			file_name: file_name.map(str::to_string),
+			format_id: format_id.map(str::to_string),
+			reader: readers::input_reader(reader),
+		});
+	}
+
+	/// Adds a JMES query, which only applies to inputs of one format if prefixed with its ID,
+	/// e.g. `csv:[?active]`.
+	pub fn add_query(&mut self, query_str: &str) -> Result<(), EachError> {
+		let (query_format, query_str) = match query_str.split_once(':') {
+			Some((prefix, rest)) => match self.formats.get_key_value(prefix) {
+				Some((&format_id, _)) => (Some(format_id), rest),
+				None => (None, query_str),
+			},
+			None => (None, query_str),
+		};
+
+		let query = jmespath::compile(query_str).map_err(|e| EachError::Usage {
+			message: format!("Invalid JMES query: {}", e),
+		})?;
+
+		self.queries.push((query_format, query));
+		Ok(())
+	}
+
+	/// Adds a field to parse, given as `FIELD:FORMAT`, e.g. `payload:json`. The field is split
+	/// from the format at the last colon, so it can have colons of its own.
+	pub fn add_parse_field(&mut self, spec: &str) -> Result<(), EachError> {
+		let (field, format_id) = spec.rsplit_once(':').ok_or_else(|| EachError::Usage {
+			message: format!("Expected FIELD:FORMAT to parse a field: {}", spec),
+		})?;
+		let (&format_id, _) =
+			self.formats
+				.get_key_value(format_id)
+				.ok_or_else(|| EachError::Usage {
+					message: format!("Unknown format: {}", format_id),
+				})?;
+
+		self.parse_fields.push((field.to_string(), format_id));
+		Ok(())
+	}
+
+	/// Replaces each string field to be parsed with what it holds, leaving any which are missing
+	/// or hold something else as they are.
+	fn parse_fields(&self, mut item: serde_json::Value) -> Result<serde_json::Value, EachError> {
+		for (field, format_id) in &self.parse_fields {
+			let text = match item.get(field) {
+				Some(serde_json::Value::String(text)) => text.clone(),
+				_ => continue,
+			};
+
+			let format = &self.formats[format_id];
+			let parsed = format
+				.parse(&mut text.as_bytes())
+				.map_err(|e| EachError::Data {
+					message: format!("Couldn't parse field {} as {}: {}", field, format_id, e),
+				})?;
+			item[field.as_str()] = parsed;
+		}
+
+		Ok(item)
+	}
+
+	/// Adds a `--where` filter, e.g. `age > 30 && status == "active"`.
+	pub fn add_filter(&mut self, expr: &str) -> Result<(), EachError> {
+		self.filters.push(Filter::parse(expr)?);
+		Ok(())
+	}
+
+	/// Reads all the items from an input, guessing its format from the file name or its header
+	/// unless one is given.
+	pub fn parse_input(
+		&self,
+		file_name: Option<&str>,
+		format_id: Option<&str>,
+		reader: Box<dyn Read>,
+	) -> Result<Vec<serde_json::Value>, EachError> {
+		let mut reader = readers::input_reader(reader);
+		let file_name = file_name.map(str::to_string);
+		let items = self.read_items(&file_name, format_id, false, &mut reader)?;
+		items.collect()
+	}
+
+	/// Reads the items from one input, with their fields parsed, flattened and matching the
+	/// filters. Those with a query for their format are parsed whole, since the query needs it
+	/// all, while the rest are parsed as their items are needed.
+	pub fn read_items<'r>(
+		&'r self,
+		file_name: &Option<String>,
+		format_id: Option<&str>,
+		strict_format: bool,
+		reader: &'r mut CachedReader,
+	) -> Result<Items<'r>, EachError> {
+		let (format_id, format) = match format_id {
+			Some(format_id) => {
+				let (&format_id, format) =
+					self.formats
+						.get_key_value(format_id)
+						.ok_or_else(|| EachError::Usage {
+							message: format!("Unknown format: {}", &format_id),
+						})?;
+
+				if strict_format && !formats::has_valid_header(reader, format.as_ref()) {
+					return Err(EachError::Data {
+						message: format!("Input is not valid {}", &format_id),
+					});
+				}
+
+				(format_id, format)
+			}
+			None => formats::guess_format(file_name, reader, &self.formats).ok_or_else(|| {
+				EachError::Data {
+					message: "Unable to guess format for input".to_string(),
+				}
+			})?,
+		};
+
+		let queries: Vec<&jmespath::Expression<'static>> = self
+			.queries
+			.iter()
+			.filter(|(query_format, _)| {
+				query_format.is_none_or(|query_format| query_format == format_id)
+			})
+			.map(|(_, query)| query)
+			.collect();
+
+		let parse_error = |e: failure::Error| EachError::Data {
+			message: format!("failed to parse input: {}", e),
+		};
+
+		let flatten = self.flatten.clone();
+		let filters = self.filters.clone();
+		let refine = move |items: Items<'r>| -> Items<'r> {
+			let items = items.map(move |item| match self.parse_fields.is_empty() {
+				true => item,
+				false => item.and_then(|value| self.parse_fields(value)),
+			});
+			let items = items.flat_map(move |item| match (item, &flatten) {
+				(Ok(value), Some(flatten)) => flatten.apply(value).into_iter().map(Ok).collect(),
+				(item, _) => vec![item],
+			});
+
+			Box::new(items.filter(move |item| match item {
+				Ok(value) => filters.iter().all(|filter| filter.matches(value)),
+				Err(_) => true,
+			}))
+		};
+
+		if queries.is_empty() {
+			let items = format.parse_items(reader).map_err(parse_error)?;
+			return Ok(refine(Box::new(
+				items.map(move |item| item.map_err(parse_error)),
+			)));
+		}
+
+		let mut values = format.parse(reader).map_err(parse_error)?;
+		for query in queries {
+			let query_result = query.search(values).map_err(|e| EachError::Data {
+				message: format!("Error evaluating JMES query: {}", e),
+			})?;
+
+			values = serde_json::to_value(query_result).map_err(|e| EachError::Data {
+				message: format!("Error converting query result to JSON value: {}", e),
+			})?;
+		}
+
+		let items = formats::into_items(values)?.into_iter().map(Ok);
+		Ok(refine(Box::new(items)))
+	}
+
+	/// Reads the items from each input in turn, as they're needed.
+	fn input_items<'r>(&'r self, inputs: &'r mut [Input]) -> Items<'r> {
+		Box::new(inputs.iter_mut().flat_map(move |input| {
+			let format_id = input.format_id.as_deref();
+			match self.read_items(
+				&input.file_name,
+				format_id,
+				self.strict_format,
+				&mut input.reader,
+			) {
+				Ok(items) => items,
+				Err(e) => Box::new(std::iter::once(Err(e))) as Items,
+			}
+		}))
+	}
+
+	/// Counts the items in the inputs which would be processed, without processing them.
+	pub fn count_inputs(&mut self) -> Result<usize, EachError> {
+		let mut inputs = std::mem::take(&mut self.inputs);
+		let items = self.input_items(&mut inputs);
+
+		let count = match self.unique {
+			Some(ref unique) => {
+				let mut items = items.collect::<Result<Vec<serde_json::Value>, EachError>>()?;
+				dedupe(&mut items, unique.field.as_deref());
+				items.len()
+			}
+			None => {
+				let mut count = 0;
+				for item in items {
+					item?;
+					count += 1;
+				}
+				count
+			}
+		};
+
+		Ok(count
+			.saturating_sub(self.skip)
+			.min(self.limit.unwrap_or(usize::MAX)))
+	}
+
+	/// Reads the items from the inputs and runs the command for each, writing the output (or
+	/// without a command, the items) to the output file or stdout in the output format.
+	pub fn run_inputs(&mut self) -> Result<(), EachError> {
+		let mut inputs = std::mem::take(&mut self.inputs);
+		let format = self
+			.formats
+			.get(self.output_format.as_str())
+			.ok_or_else(|| EachError::Usage {
+				message: format!("Unknown output format: {}", &self.output_format),
+			})?;
+
+		// Inputs are parsed as their items are needed, so where nothing needs them all at once
+		// they're processed in batches, without holding the whole input in memory.
+		let items = self.input_items(&mut inputs);
+		let needs_all = self.unique.is_some()
+			|| self.sort.is_some()
+			|| self
+				.action
+				.as_ref()
+				.is_some_and(|action| action.review || action.progress || action.uses_total());
+
+		// Output is only written with a command if its output is collected, and formats which can
+		// write values one at a time are written to as they're produced.
+		let output = self.action.as_ref().is_none_or(|action| action.collect);
+		let mut out = match output && format.writes_values() && self.output_template.is_none() {
+			true => Some(self.open_output()?),
+			false => None,
+		};
+		let mut output_values = Vec::new();
+		let mut emit = |values: Vec<serde_json::Value>| -> Result<(), EachError> {
+			let out = match out {
+				Some(ref mut out) => out,
+				None => {
+					output_values.extend(values);
+					return Ok(());
+				}
+			};
+
+			for value in &values {
+				format
+					.write_value(value, out)
+					.map_err(|e| EachError::Data {
+						message: format!("serialize error: {:?}", e),
+					})?;
+			}
+
+			Ok(out.flush()?)
+		};
+
+		// Items from all the inputs are processed together, so they're numbered as one sequence
+		// and can be reviewed at once.
+		let started = Instant::now();
+		let result = match needs_all {
+			false => self.process_batches(items, &mut emit),
+			true => {
+				let mut items = items.collect::<Result<Vec<serde_json::Value>, EachError>>()?;
+
+				if let Some(ref unique) = self.unique {
+					dedupe(&mut items, unique.field.as_deref());
+				}
+
+				if let Some(ref sort) = self.sort {
+					items.sort_by(|a, b| {
+						let ordering =
+							compare_json(field_value(a, &sort.field), field_value(b, &sort.field));
+						match sort.desc {
+							true => ordering.reverse(),
+							false => ordering,
+						}
+					});
+				}
+
+				let items: Vec<serde_json::Value> = items
+					.into_iter()
+					.skip(self.skip)
+					.take(self.limit.unwrap_or(usize::MAX))
+					.collect();
+				self.summary.items.store(items.len(), Ordering::SeqCst);
+				self.run(&items).and_then(&mut emit)
+			}
+		};
+
+		// Shown even if processing stopped at a failure.
+		let summary = &self.summary;
+		if self.action.is_some() && self.stats {
+			eprintln!(
+				"{} items, {} commands run, {} succeeded, {} failed in {:.2}s",
+				summary.items.load(Ordering::SeqCst),
+				summary.ran.load(Ordering::SeqCst),
+				summary.succeeded.load(Ordering::SeqCst),
+				summary.failed.load(Ordering::SeqCst),
+				started.elapsed().as_secs_f64()
+			);
+		}
+
+		result?;
+
+		if let Some(ref action) = self.action {
+			if action.keep_going {
+				let failed = summary.failed.load(Ordering::SeqCst);
+				eprintln!(
+					"{} succeeded, {} failed",
+					summary.succeeded.load(Ordering::SeqCst),
+					failed
+				);
+
+				if failed > 0 {
+					return Err(EachError::Data {
+						message: format!("{} commands failed", failed),
+					});
+				}
+			}
+		}
+
+		if let Some(out) = out {
+			return Ok(out.finish()?);
+		}
+		if !output {
+			return Ok(());
+		}
+
+		// With a command this is handled as each one runs.
+		if let Some(ref template) = self.output_template {
+			return self.write_per_item(output_values, template, format.as_ref());
+		}
+
+		let mut out = self.open_output()?;
+		if let Err(e) = format.write(output_values, &mut out) {
+			return Err(EachError::Data {
+				message: format!("serialize error: {:?}", e),
+			});
+		}
+
+		Ok(out.finish()?)
+	}
+
+	/// Processes items in batches as they're read, skipping the first `skip` and stopping after
+	/// `limit`, and passes on the results (or the items themselves, without a command) to `emit`.
+	/// The number processed is counted in the summary, even if it stopped at a failure.
+	fn process_batches(
+		&self,
+		items: Items,
+		emit: &mut dyn FnMut(Vec<serde_json::Value>) -> Result<(), EachError>,
+	) -> Result<(), EachError> {
+		// Without a command there's no need to copy the items.
+		let mut process_batch = |batch: Vec<serde_json::Value>, offset: usize| match self.action {
+			Some(_) => emit(self.process(&batch, offset, None)?),
+			None => emit(batch),
+		};
+
+		let limit = self.limit.unwrap_or(usize::MAX);
+		let mut skip = self.skip;
+		let mut count = 0;
+		let mut batch = Vec::with_capacity(BATCH_LEN);
+		for item in items {
+			if count == limit {
+				break;
+			}
+
+			let item = item?;
+			if skip > 0 {
+				skip -= 1;
+				continue;
+			}
+
+			count += 1;
+			self.summary.items.store(count, Ordering::SeqCst);
+			batch.push(item);
+			if batch.len() == BATCH_LEN {
+				process_batch(std::mem::take(&mut batch), count - BATCH_LEN)?;
+			}
+		}
+
+		let offset = count - batch.len();
+		process_batch(batch, offset)
+	}
+
+	/// The output file, or stdout.
+	fn open_output(&self) -> Result<writers::Output, EachError> {
+		Ok(match self.output {
+			Some(ref output_path) => {
+				writers::create_file(output_path, self.append).map_err(|e| EachError::Data {
+					message: format!("Couldn't create file {}: {}", output_path.display(), e),
+				})?
+			}
+			None => writers::Output::stdout(),
+		})
+	}
+
+	/// Writes each item to the file its output template renders, grouping any which share a file
+	/// if `output_share` is set.
+	fn write_per_item(
+		&self,
+		values: Vec<serde_json::Value>,
+		template: &str,
+		format: &dyn Format,
+	) -> Result<(), EachError> {
+		let mut templates = Handlebars::new();
+		templates.register_escape_fn(handlebars::no_escape);
+		helpers::register(&mut templates);
+		templates
+			.register_template_string("output", template)
+			.map_err(|e| EachError::Usage {
+				message: format!("Invalid template: {:?}", e),
+			})?;
+
+		let total = values.len();
+		let mut files: IndexMap<PathBuf, Vec<serde_json::Value>> = IndexMap::new();
+		for (index, value) in values.into_iter().enumerate() {
+			let path = action::render_item(&templates, "output", &value, index, Some(total))
+				.map_err(|e| EachError::Data {
+					message: format!("failed to render output path: {}", e),
+				})?;
+
+			match files.get_mut(Path::new(&path)) {
+				Some(values) if self.output_share => values.push(value),
+				Some(_) => {
+					return Err(EachError::Data {
+						message: format!("Output file {:?} is used by more than one item", path),
+					})
+				}
+				None => {
+					files.insert(PathBuf::from(path), vec![value]);
+				}
+			}
+		}
+
+		for (path, values) in files {
+			if let Some(parent) = path.parent() {
+				std::fs::create_dir_all(parent)?;
+			}
+
+			let mut out =
+				writers::create_file(&path, self.append).map_err(|e| EachError::Data {
+					message: format!("Couldn't create file {:?}: {}", path, e),
+				})?;
+			format
+				.write(values, &mut out)
+				.map_err(|e| EachError::Data {
+					message: format!("serialize error: {:?}", e),
+				})?;
+			out.finish()?;
+		}
+
+		Ok(())
+	}
+
+	/// Runs the command for each value, returning their output if it's collected. Without a
+	/// command the values are returned as they are.
+	pub fn run(&self, values: &[serde_json::Value]) -> Result<Vec<serde_json::Value>, EachError> {
+		self.process(values, 0, Some(values.len()))
+	}
+
+	/// Like `run`, for values numbered from `offset` (as `@index` in templates) out of `total`
+	/// (as `@total`, along with `@last`) if it's known.
+	pub fn process(
+		&self,
+		values: &[serde_json::Value],
+		offset: usize,
+		total: Option<usize>,
+	) -> Result<Vec<serde_json::Value>, EachError> {
+		let action = match self.action {
+			Some(ref action) => action,
+			None => return Ok(values.to_vec()),
+		};
+
+		// In strict mode, a template referring to a field the first item doesn't have stops the
+		// run before any command has started, rather than failing each item as it comes up.
+		if offset == 0 && action.is_strict() && !action.keep_going {
+			if let Some(first) = values.first() {
+				action
+					.prepare(first, 0, total)
+					.map_err(|e| EachError::Data {
+						message: format!("Templates don't work with the first item: {}", e),
+					})?;
+			}
+		}
+
+		if action.review && !action.dry_run && !review(values, total, action)? {
+			return Ok(Vec::new());
+		}
+
+		let progress = match action.progress {
+			true => Some(progress_bar(values.len(), action.color)),
+			false => None,
+		};
+
+		let summary = &self.summary;
+		// Collecting into a Vec keeps the input order, however the items were scheduled.
+		let results: Result<Vec<Option<serde_json::Value>>, EachError> = values
+			.par_iter()
+			.enumerate()
+			.map(
+				|(i, value)| -> Result<Option<serde_json::Value>, EachError> {
+					// Once interrupted or past the deadline the run stops, even with --keep-going.
+					if is_interrupted() {
+						return Err(EachError::Interrupted);
+					}
+					if action.past_deadline() {
+						return Err(EachError::Data {
+							message: "Deadline passed before every item was processed".to_string(),
+						});
+					}
+
+					let result = process_value(value, offset + i, total, action, summary);
+					if let Some(ref progress) = progress {
+						progress.inc(1);
+					}
+
+					// Commands which succeed are counted as they finish, since dry runs and
+					// declined prompts don't run anything to succeed.
+					if result.is_err() {
+						summary.failed.fetch_add(1, Ordering::SeqCst);
+					}
+
+					if !action.keep_going {
+						return result;
+					}
+
+					result.or_else(|e| {
+						eprintln!("Error: {}", e);
+						Ok(None)
+					})
+				},
+			)
+			.collect();
+
+		if let Some(progress) = progress {
+			progress.finish();
+		}
+
+		Ok(results?.into_iter().flatten().collect())
+	}
+}
+
+/// How many items are read before processing them, when they don't need to be read all at once.
+const BATCH_LEN: usize = 1024;
+
+/// Expands a glob pattern into the paths it matches, in order, and a directory into the
+/// files in it which have a known format. Anything else is left as is.
+fn expand_input(
+	input_arg: &str,
+	recursive: bool,
+	formats: &IndexMap<&'static str, Box<dyn Format>>,
+) -> Result<Vec<String>, EachError> {
+	let is_url = input_arg.starts_with("http://") || input_arg.starts_with("https://");
+	if is_url {
+		return Ok(vec![input_arg.to_string()]);
+	}
+
+	let mut paths = Vec::new();
+	if input_arg.contains(['*', '?', '[']) {
+		let matches = glob::glob(input_arg).map_err(|e| EachError::Usage {
+			message: format!("Invalid glob pattern {}: {}", input_arg, e),
+		})?;
+
+		for path in matches {
+			paths.push(path.map_err(|e| EachError::Data {
+				message: format!("Couldn't read {}: {}", e.path().display(), e),
+			})?);
+		}
+
+		if paths.is_empty() {
+			return Err(EachError::Usage {
+				message: format!("No files match {}", input_arg),
+			});
+		}
+
+		paths.sort();
+	} else {
+		paths.push(PathBuf::from(input_arg));
+	}
+
+	let mut input_paths = Vec::new();
+	for path in paths {
+		if !path.is_dir() {
+			input_paths.push(path.to_string_lossy().to_string());
+			continue;
+		}
+
+		let walker = WalkDir::new(&path)
+			.min_depth(1)
+			.max_depth(if recursive { usize::MAX } else { 1 })
+			.sort_by_file_name();
+
+		for entry in walker {
+			let entry = entry.map_err(|e| EachError::Data {
+				message: format!("Couldn't read directory {}: {}", path.display(), e),
+			})?;
+			if !entry.file_type().is_file() {
+				continue;
+			}
+
+			let name = entry.file_name().to_string_lossy();
+			let name = name.strip_suffix(".gz").unwrap_or(&name);
+			if formats::format_for_name(name, formats).is_none() {
+				debug!(
+					"skipping file with unknown format: {}",
+					entry.path().display()
+				);
+				continue;
+			}
+
+			input_paths.push(entry.path().to_string_lossy().to_string());
+		}
+	}
+
+	Ok(input_paths)
+}
+
+/// Drops items which are the same as an earlier one, or whose `field` is.
+fn dedupe(items: &mut Vec<serde_json::Value>, field: Option<&str>) {
+	let mut seen = HashSet::new();
+	items.retain(|item| {
+		let key = match field {
+			Some(field) => field_value(item, field),
+			None => item,
+		};
+
+		seen.insert(canonical_json(key))
+	});
+}
+
+/// The value of an item's field, which is null if it's missing.
+fn field_value<'a>(item: &'a serde_json::Value, field: &str) -> &'a serde_json::Value {
+	static NULL: serde_json::Value = serde_json::Value::Null;
+	item.get(field).unwrap_or(&NULL)
+}
+
+/// Serializes a value with the keys of each object sorted, so equal values serialize the same.
+fn canonical_json(value: &serde_json::Value) -> String {
+	fn sorted(value: &serde_json::Value) -> serde_json::Value {
+		match value {
+			serde_json::Value::Object(obj) => {
+				let mut entries: Vec<(&String, &serde_json::Value)> = obj.iter().collect();
+				entries.sort_by_key(|(k, _)| *k);
+				entries
+					.into_iter()
+					.map(|(k, v)| (k.clone(), sorted(v)))
+					.collect::<serde_json::Map<_, _>>()
+					.into()
+			}
+			serde_json::Value::Array(values) => values.iter().map(sorted).collect(),
+			_ => value.clone(),
+		}
+	}
+
+	sorted(value).to_string()
+}
+
+/// Orders values of different types as null, booleans, numbers, strings, arrays then objects.
+fn compare_json(a: &serde_json::Value, b: &serde_json::Value) -> std::cmp::Ordering {
+	use serde_json::Value;
+
+	fn rank(value: &Value) -> u8 {
+		match value {
+			Value::Null => 0,
+			Value::Bool(_) => 1,
+			Value::Number(_) => 2,
+			Value::String(_) => 3,
+			Value::Array(_) => 4,
+			Value::Object(_) => 5,
+		}
+	}
+
+	match (a, b) {
+		(Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+		(Value::Number(a), Value::Number(b)) => {
+			let (a, b) = (a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+			a.total_cmp(&b)
+		}
+		(Value::String(a), Value::String(b)) => a.cmp(b),
+		(Value::Array(a), Value::Array(b)) => a
+			.iter()
+			.zip(b)
+			.map(|(a, b)| compare_json(a, b))
+			.find(|ordering| ordering.is_ne())
+			.unwrap_or_else(|| a.len().cmp(&b.len())),
+		(Value::Object(_), Value::Object(_)) => canonical_json(a).cmp(&canonical_json(b)),
+		_ => rank(a).cmp(&rank(b)),
+	}
+}
+
+/// Set once the run is interrupted, after which no more commands are started.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Stops starting commands, e.g. on Ctrl-C, while any already running are left to finish.
+/// Returns whether it had already been interrupted.
+pub fn interrupt() -> bool {
+	INTERRUPTED.swap(true, Ordering::SeqCst)
+}
+
+/// Whether `interrupt` has been called.
+pub fn is_interrupted() -> bool {
+	INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Held while prompting, so prompts from parallel items are shown one at a time.
+static PROMPT_LOCK: Mutex<()> = Mutex::new(());
+
+/// The theme for prompts, which only has colors if they're wanted.
+fn prompt_theme(action: &Action) -> Box<dyn Theme> {
+	match action.color {
+		true => Box::new(ColorfulTheme::default()),
+		false => Box::new(SimpleTheme),
+	}
+}
+
+/// A bar showing how many of `len` items are done and how long it's taken so far.
+fn progress_bar(len: usize, color: bool) -> ProgressBar {
+	let template = match color {
+		true => "{elapsed_precise:.dim} [{wide_bar:.cyan/blue}] {pos}/{len}",
+		false => "{elapsed_precise} [{wide_bar}] {pos}/{len}",
+	};
+	let style = ProgressStyle::with_template(template).expect("progress template is valid");
+
+	// It's drawn on stderr so it doesn't get mixed up with the output.
+	ProgressBar::with_draw_target(Some(len as u64), ProgressDrawTarget::stderr()).with_style(style)
+}
+
+/// Lists every command and asks whether to run them all. Any template error stops everything
+/// before a single command has run.
+fn review(
+	values: &[serde_json::Value],
+	total: Option<usize>,
+	action: &Action,
+) -> Result<bool, EachError> {
+	for (i, value) in values.iter().enumerate() {
+		let cmd = prepare(value, i, total, action)?;
+		eprintln!("{}: {}", i + 1, action.describe(&cmd, action.prompt_stdin));
+	}
+
+	Ok(Confirm::with_theme(&*prompt_theme(action))
+		.with_prompt(format!("Run these {} commands?", values.len()))
+		.default(false)
+		.interact()?)
+}
+
+fn prepare(
+	value: &serde_json::Value,
+	index: usize,
+	total: Option<usize>,
+	action: &Action,
+) -> Result<PreparedCommand, EachError> {
+	action
+		.prepare(value, index, total)
+		.map_err(|e| EachError::Data {
+			message: format!("failed to prepare command: {:?}", e),
+		})
+}
+
+fn process_value(
+	value: &serde_json::Value,
+	index: usize,
+	total: Option<usize>,
+	action: &Action,
+	summary: &Summary,
+) -> Result<Option<serde_json::Value>, EachError> {
+	let cmd = prepare(value, index, total, action)?;
+
+	if action.dry_run {
+		println!("{}", action.describe(&cmd, true));
+		return Ok(None);
+	}
+
+	if action.prompt {
+		// Only the prompt is locked, so confirmed commands can still run in parallel.
+		let confirmed = {
+			let _lock = PROMPT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+			Confirm::with_theme(&*prompt_theme(action))
+				.with_prompt(action.prompt(&cmd))
+				.interact()?
+		};
+
+		// Answering no skips the item without it counting as a failure.
+		if !confirmed {
+			return Ok(None);
+		}
+	}
+
+	if action.print_command {
+		// eprintln! holds stderr's lock for the whole line, so lines from parallel items don't mix.
+		eprintln!("{}", action.describe(&cmd, false));
+	}
+
+	summary.ran.fetch_add(1, Ordering::SeqCst);
+	let output = action.run(cmd).map_err(|e| EachError::Data {
+		message: format!("failed to run command: {:?}", e),
+	})?;
+	summary.succeeded.fetch_add(1, Ordering::SeqCst);
+	Ok(output)
+}
@@ -1,203 +1,746 @@
-mod action;
-mod errors;
-mod formats;
-mod readers;
 mod tests;
 
 use clap::{Arg, Command};
-use dialoguer::Confirm;
 use indexmap::IndexMap;
-use log::info;
-use rayon::prelude::*;
-use std::path::Path;
+use log::{info, warn};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use action::Action;
-use errors::EachError;
-use formats::{Format, DEFAULT_FORMAT};
-use readers::{CachedReader, FileReader};
+use each::formats::{self, Format, PRINT0_FORMAT};
+use each::{Action, Each, EachError, Flatten, Sort, Unique};
+
+/// The exit code when interrupted by Ctrl-C, as shells report for SIGINT.
+const EXIT_INTERRUPTED: i32 = 128 + 2;
 
 fn main() {
 	env_logger::init();
 
 	let formats = formats::load_formats();
 	let format_ids: Vec<&str> = formats.iter().map(|(&k, _)| k).collect();
+	let output_format_ids: Vec<&str> = formats
+		.iter()
+		.filter(|(_, format)| format.can_write())
+		.map(|(&k, _)| k)
+		.collect();
 
+	// Without a subcommand each infers the mode from whether a command was given, so the
+	// top level takes every argument. A program called `convert` or `run` needs `--` first.
 	let mut args = Command::new("each")
 		.version("0.2")
 		.author("Arpad Ray <hello@arpad.pizza>")
 		.about("Build and execute command lines from structured input")
 		.trailing_var_arg(true)
+		.args_conflicts_with_subcommands(true);
+	args = exec_args(output_args(
+		input_args(args, &format_ids, &formats),
+		&output_format_ids,
+	));
+	args = args.arg(Arg::new("command").multiple_occurrences(true));
+
+	let mut convert = Command::new("convert").about("Convert structured input into another format");
+	convert = output_args(
+		input_args(convert, &format_ids, &formats),
+		&output_format_ids,
+	);
+
+	let mut exec = Command::new("run")
+		.about("Execute a command for each item of structured input")
+		.trailing_var_arg(true);
+	exec = exec_args(output_args(
+		input_args(exec, &format_ids, &formats),
+		&output_format_ids,
+	));
+	exec = exec.arg(
+		Arg::new("command")
+			.multiple_occurrences(true)
+			.required_unless_present_any(["command-file", "command-field", "map"]),
+	);
+
+	args = args.subcommand(convert).subcommand(exec);
+
+	std::process::exit(match run(args, formats) {
+		Ok(_) => exitcode::OK,
+		Err(e) => {
+			eprintln!("Error: {}", e);
+			match e {
+				// Commands cut short by the interruption fail too, but it's the reason they did.
+				_ if each::is_interrupted() => EXIT_INTERRUPTED,
+				EachError::Interrupted => EXIT_INTERRUPTED,
+				EachError::Usage { message: _ } => exitcode::USAGE,
+				EachError::Data { message: _ } => exitcode::DATAERR,
+				EachError::Io { inner: _ } => exitcode::IOERR,
+			}
+		}
+	})
+}
+
+fn input_args<'a>(
+	mut args: Command<'a>,
+	format_ids: &'a [&'a str],
+	formats: &IndexMap<&'static str, Box<dyn Format>>,
+) -> Command<'a> {
+	args = args
 		.arg(
 			Arg::new("input")
 				.short('i')
 				.long("input")
 				.value_name("FILE")
 				.multiple_occurrences(true)
-				.help("Read input from FILE instead of stdin")
+				.help("Read input from FILE (or a URL, glob or directory) instead of stdin")
 				.takes_value(true),
 		)
+		.arg(
+			Arg::new("recursive")
+				.short('r')
+				.long("recursive")
+				.help("Read files in subdirectories of any directory given with --input"),
+		)
 		.arg(
 			Arg::new("format")
 				.short('f')
 				.long("format")
 				.value_name("FORMAT")
-				.help("Input file format")
+				.multiple_occurrences(true)
+				.use_value_delimiter(true)
+				.help("Input file format, or a list of them for each -i in turn (the rest are guessed)")
 				.takes_value(true)
-				.possible_values(format_ids.as_slice()),
+				.possible_values(format_ids),
+		)
+		.arg(
+			Arg::new("strict-format")
+				.long("strict-format")
+				.requires("format")
+				.help("Fail if the input doesn't look like the given --format"),
 		)
 		.arg(
 			Arg::new("query")
 				.short('q')
 				.long("query")
 				.value_name("QUERY")
-				.help("JMES query to apply to each input file")
+				.multiple_occurrences(true)
+				.help("JMES query to apply to each input (or FORMAT:QUERY for just that format), repeat to chain")
 				.takes_value(true),
 		)
 		.arg(
-			Arg::new("output-format")
-				.short('F')
-				.long("output-format")
-				.value_name("FORMAT")
-				.help("Output file format")
-				.takes_value(true)
-				.possible_values(format_ids.as_slice()),
+			Arg::new("query-file")
+				.short('Q')
+				.long("query-file")
+				.value_name("PATH")
+				.conflicts_with("query")
+				.help("File containing a JMES query to apply to each input, as for --query")
+				.takes_value(true),
 		)
 		.arg(
-			Arg::new("prompt")
-				.short('p')
-				.long("interactive")
-				.help("Prompt for each value"),
+			Arg::new("where")
+				.short('w')
+				.long("where")
+				.value_name("EXPR")
+				.multiple_occurrences(true)
+				.help("Only use items matching EXPR, e.g. 'age > 30 && status == \"active\"', repeat to require all")
+				.takes_value(true),
 		)
 		.arg(
-			Arg::new("max-procs")
-				.short('P')
-				.long("max-procs")
-				.value_name("max-procs")
-				.help("Run up to max-procs processes at a time")
+			Arg::new("parse-field")
+				.long("parse-field")
+				.value_name("FIELD:FORMAT")
+				.multiple_occurrences(true)
+				.help("Replace each item's FIELD, if it's a string, with what it holds parsed as FORMAT, e.g. payload:json")
 				.takes_value(true),
 		)
 		.arg(
-			Arg::new("stdin")
-				.short('s')
-				.long("stdin")
-				.value_name("TEMPLATE")
-				.help("Template string to pass to the stdin of each process")
+			Arg::new("flatten")
+				.long("flatten")
+				.value_name("FIELD")
+				.help("Expand each item into one per element of its FIELD array, merging objects or putting others under value")
 				.takes_value(true),
 		)
 		.arg(
-			Arg::new("stdin-file")
-				.short('S')
-				.long("stdin-file")
-				.value_name("PATH")
-				.help("File containing template string to pass to the stdin of each process")
+			Arg::new("flatten-drop-missing")
+				.long("flatten-drop-missing")
+				.requires("flatten")
+				.help("Drop items without a FIELD array with --flatten, rather than keeping them as they are"),
+		)
+		.arg(
+			Arg::new("unique")
+				.long("unique")
+				.help("Drop items which are the same as an earlier one, whatever the order of their keys"),
+		)
+		.arg(
+			Arg::new("unique-by")
+				.long("unique-by")
+				.value_name("FIELD")
+				.help("Drop items whose FIELD is the same as an earlier item's")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::new("sort")
+				.long("sort")
+				.value_name("FIELD")
+				.help("Sort items by FIELD (nulls, then booleans, numbers, strings, arrays and objects)")
+				.takes_value(true),
+		)
+		.arg(
+			Arg::new("sort-desc")
+				.long("sort-desc")
+				.requires("sort")
+				.help("Sort in descending order with --sort"),
+		)
+		.arg(
+			Arg::new("skip")
+				.long("skip")
+				.value_name("N")
+				.help("Skip the first N items, counting across all inputs")
 				.takes_value(true),
 		)
 		.arg(
-			Arg::new("prompt-stdin")
-				.long("prompt-stdin")
-				.help("Include stdin template in interactive prompt (implies -p)"),
+			Arg::new("limit")
+				.long("limit")
+				.value_name("N")
+				.help("Use at most N items (after any --skip), counting across all inputs")
+				.takes_value(true),
 		);
 
-	for (_, format) in &formats {
+	for (_, format) in formats {
 		args = format.add_arguments(args);
 	}
 
-	args = args.arg(Arg::new("command").multiple_occurrences(true));
+	args
+}
 
-	std::process::exit(match run(args, formats) {
-		Ok(_) => exitcode::OK,
-		Err(e) => {
-			eprintln!("Error: {}", e);
-			match e {
-				EachError::Usage { message: _ } => exitcode::USAGE,
-				EachError::Data { message: _ } => exitcode::DATAERR,
-				EachError::Io { inner: _ } => exitcode::IOERR,
-			}
-		}
-	})
+fn output_args<'a>(args: Command<'a>, format_ids: &'a [&'a str]) -> Command<'a> {
+	args.arg(
+		Arg::new("output")
+			.short('o')
+			.long("output")
+			.value_name("FILE")
+			.help("Write output to FILE instead of stdout")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("output-format")
+			.short('F')
+			.long("output-format")
+			.value_name("FORMAT")
+			.help("Output file format")
+			.takes_value(true)
+			.possible_values(format_ids),
+	)
+	.arg(
+		Arg::new("output-template")
+			.long("output-template")
+			.value_name("TEMPLATE")
+			.conflicts_with("output")
+			.help("Write each item (or its command's output) to the file TEMPLATE renders to")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("template-delimiters")
+			.long("template-delimiters")
+			.value_name("OPEN CLOSE")
+			.help("Mark template expressions with OPEN and CLOSE, e.g. '<< >>', instead of {{ and }}, which are then left as they are")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("append")
+			.long("append")
+			.help("Append to output files which already exist rather than replacing them, implying --output-append"),
+	)
+	.arg(
+		Arg::new("output-append")
+			.long("output-append")
+			.requires("output-template")
+			.help("Let items share an --output-template file, appending to it rather than failing"),
+	)
+	.arg(
+		Arg::new("count")
+			.long("count")
+			.help("Just print how many items there are, without running a command or writing them"),
+	)
+	.arg(Arg::new("print0").long("print0").help(
+		"End lines, ndjson and logfmt output records with NUL instead of newline [defaults -F to lines]",
+	))
+	.arg(
+		Arg::new("indent")
+			.long("indent")
+			.value_name("N")
+			.help(
+				"Number of spaces to indent nested output by [defaults to each format's own style]",
+			)
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("compact")
+			.long("compact")
+			.conflicts_with("indent")
+			.help("Write json output on a single line, without indentation"),
+	)
+}
+
+fn exec_args(args: Command) -> Command {
+	args.arg(
+		Arg::new("prompt")
+			.short('p')
+			.long("interactive")
+			.help("Prompt for each value"),
+	)
+	.arg(
+		Arg::new("max-procs")
+			.short('P')
+			.long("max-procs")
+			.value_name("max-procs")
+			.help("Run up to max-procs processes at a time, a percentage of CPUs (e.g. 50%) or a multiple of them (cpus*4) [default: one per CPU, or 1 with --interactive]")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("stdin")
+			.short('s')
+			.long("stdin")
+			.value_name("TEMPLATE")
+			.help("Template string to pass to the stdin of each process")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("stdin-file")
+			.short('S')
+			.long("stdin-file")
+			.value_name("PATH")
+			.conflicts_with("stdin")
+			.help("File containing template string to pass to the stdin of each process")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("stdin-field")
+			.long("stdin-field")
+			.value_name("FIELD")
+			.conflicts_with_all(&["stdin", "stdin-file"])
+			.help("Field to pass to the stdin of each process as it is, without a template")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("arg-file")
+			.long("arg-file")
+			.value_name("PATH")
+			.multiple_occurrences(true)
+			.help("File containing a template for one more argument, after those on the command line")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("stream")
+			.long("stream")
+			.help("Show output as each command runs, even with max-procs above 1"),
+	)
+	.arg(
+		Arg::new("collect")
+			.long("collect")
+			.help("Collect the output of each command and write it in the output format"),
+	)
+	.arg(
+		Arg::new("collect-json")
+			.long("collect-json")
+			.help("Like --collect, but parse the output of each command as JSON"),
+	)
+	.arg(
+		Arg::new("dry-run")
+			.short('n')
+			.long("dry-run")
+			.help("Print each command (and its stdin) instead of running it"),
+	)
+	.arg(
+		Arg::new("print-command")
+			.short('v')
+			.long("print-command")
+			.help("Print each command to stderr as it's run"),
+	)
+	.arg(
+		Arg::new("strict")
+			.long("strict")
+			.help("Fail an item if a template refers to a field it doesn't have"),
+	)
+	.arg(
+		Arg::new("keep-going")
+			.long("keep-going")
+			.help("Carry on after a command fails, and report how many failed at the end"),
+	)
+	.arg(
+		Arg::new("ignore-exit")
+			.long("ignore-exit")
+			.help("Don't treat a command exiting with a non-zero status as a failure"),
+	)
+	.arg(
+		Arg::new("timeout")
+			.long("timeout")
+			.value_name("SECONDS")
+			.help("Kill each process if it runs for longer than SECONDS")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("deadline")
+			.long("deadline")
+			.value_name("SECONDS")
+			.help("Stop if the whole run takes longer than SECONDS, killing any processes still running")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("retries")
+			.long("retries")
+			.value_name("N")
+			.help("Run a failed command again, up to N more times")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("retry-delay")
+			.long("retry-delay")
+			.value_name("MS")
+			.requires("retries")
+			.help("Milliseconds to wait before each retry [default: 0]")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("retry-backoff")
+			.long("retry-backoff")
+			.requires("retry-delay")
+			.help("Double the retry delay after each retry"),
+	)
+	.arg(
+		Arg::new("shell")
+			.long("shell")
+			.help("Render the command line as one template and run it with $SHELL (or sh) -c"),
+	)
+	.arg(
+		Arg::new("command-file")
+			.long("command-file")
+			.value_name("PATH")
+			.conflicts_with("command")
+			.help("File containing a shell script template to run for each item, instead of a command")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("map")
+			.long("map")
+			.value_name("TEMPLATE")
+			.conflicts_with_all(&["command", "command-file", "command-field", "shell"])
+			.help("Print what TEMPLATE renders for each item, rather than running a command")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("command-field")
+			.long("command-field")
+			.value_name("FIELD")
+			.conflicts_with_all(&["command-file", "shell"])
+			.help("Run the command in each item's FIELD: a string run with $SHELL (or sh) -c, or an array of the program and its arguments. Any command line arguments are templates for more. Only use this with data you trust")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("cwd")
+			.long("cwd")
+			.value_name("TEMPLATE")
+			.help("Template for the working directory of each process")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("env")
+			.long("env")
+			.value_name("KEY=TEMPLATE")
+			.multiple_occurrences(true)
+			.help("Set an environment variable for each process, the value being a template")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("env-clear")
+			.long("env-clear")
+			.help("Don't inherit the environment, only pass variables given with --env"),
+	)
+	.arg(
+		Arg::new("stats")
+			.long("stats")
+			.help("Print how many items and commands there were, how many failed and how long it took"),
+	)
+	.arg(
+		Arg::new("progress")
+			.long("progress")
+			.help("Show a progress bar on stderr, if it's a terminal"),
+	)
+	.arg(
+		Arg::new("color")
+			.long("color")
+			.value_name("WHEN")
+			.possible_values(["auto", "always", "never"])
+			.default_value("auto")
+			.help("Color prompts and the progress bar: auto (if stderr is a terminal and NO_COLOR isn't set), always or never")
+			.takes_value(true),
+	)
+	.arg(
+		Arg::new("review")
+			.long("review")
+			.help("Show every command first and ask once before running any of them"),
+	)
+	.arg(
+		Arg::new("prompt-stdin")
+			.long("prompt-stdin")
+			.help("Include stdin template in interactive prompt (implies -p)"),
+	)
 }
 
 fn run(
 	args: Command,
 	mut formats: IndexMap<&'static str, Box<dyn Format>>,
 ) -> Result<(), EachError> {
-	let arg_matches = args.get_matches();
-	info!("arguments: {:?}", arg_matches);
+	let all_matches = args.get_matches();
+	info!("arguments: {:?}", all_matches);
+
+	let (arg_matches, exec) = match all_matches.subcommand() {
+		Some(("convert", matches)) => (matches, false),
+		Some(("run", matches)) => (matches, true),
+		_ => (
+			&all_matches,
+			all_matches.is_present("command")
+				|| all_matches.is_present("command-file")
+				|| all_matches.is_present("command-field")
+				|| all_matches.is_present("map"),
+		),
+	};
 
 	for (format_id, ref mut format) in &mut formats {
 		format
-			.set_arguments(&arg_matches)
+			.set_arguments(arg_matches)
 			.map_err(|e| EachError::Usage {
 				message: format!("Invalid argument for format {}: {}", format_id, e),
 			})?;
 	}
 
-	let max_procs = if let Some(max_procs_str) = arg_matches.value_of("max-procs") {
-		max_procs_str
-			.parse::<usize>()
-			.map_err(|e| EachError::Usage {
-				message: format!("Invalid max-procs: {} ({})", &max_procs_str, e),
-			})?
-	} else {
-		1
-	};
+	if exec {
+		// Run in parallel by default, unless prompting, where answers could get mixed up, or
+		// printing with --map, which has nothing to gain and would lose the order.
+		let max_procs = match arg_matches.value_of("max-procs") {
+			Some(max_procs_str) => parse_max_procs(max_procs_str)?,
+			None if arg_matches.is_present("prompt") || arg_matches.is_present("prompt-stdin") => 1,
+			None if arg_matches.is_present("map") => 1,
+			None => num_cpus::get(),
+		};
 
-	rayon::ThreadPoolBuilder::new()
-		.num_threads(max_procs)
-		.build_global()
-		.expect("build_global already called");
+		rayon::ThreadPoolBuilder::new()
+			.num_threads(max_procs)
+			.build_global()
+			.expect("build_global already called");
 
-	let mut readers: Vec<(Option<String>, CachedReader)> = Vec::new();
+		// The first Ctrl-C lets running commands finish, the second doesn't wait. Without a
+		// command there's nothing to wait for, so Ctrl-C stops straight away as usual.
+		let handled = ctrlc::set_handler(|| {
+			if each::interrupt() {
+				std::process::exit(EXIT_INTERRUPTED);
+			}
+			eprintln!(
+				"Interrupted, waiting for running commands to finish (Ctrl-C again to stop now)"
+			);
+		});
+		if let Err(e) = handled {
+			warn!("Unable to handle Ctrl-C: {}", e);
+		}
+	}
 
-	if let Some(input_paths) = arg_matches.values_of("input") {
-		for input_path in input_paths {
-			let path = Path::new(&input_path);
-			let ext = path
-				.extension()
-				.map(|ext| ext.to_string_lossy().to_string());
-			let reader = Box::new(FileReader::new(&input_path).map_err(|e| EachError::Data {
-				message: format!("Couldn't open file {}: {}", &input_path, e),
-			})?);
+	let mut each = Each::with_formats(formats);
 
-			let cached = CachedReader::new(reader);
+	// A single format applies to every input, while several go with each -i in turn.
+	let format_ids: Vec<&str> = arg_matches
+		.values_of("format")
+		.into_iter()
+		.flatten()
+		.collect();
+	let input_count = arg_matches.occurrences_of("input").max(1) as usize;
+	if format_ids.len() > 1 && format_ids.len() > input_count {
+		return Err(EachError::Usage {
+			message: format!(
+				"{} formats given for {} inputs",
+				format_ids.len(),
+				input_count
+			),
+		});
+	}
+	let input_format = |i: usize| match format_ids.len() {
+		1 => Some(format_ids[0]),
+		_ => format_ids.get(i).copied(),
+	};
 
-			readers.push((ext, cached));
+	if let Some(input_args) = arg_matches.values_of("input") {
+		for (i, input_arg) in input_args.enumerate() {
+			each.add_input(
+				input_arg,
+				input_format(i),
+				arg_matches.is_present("recursive"),
+			)?;
 		}
 	} else if atty::is(atty::Stream::Stdin) {
 		return Err(EachError::Usage {
 			message: "No input provided".to_owned(),
 		});
 	} else {
-		let reader = Box::new(std::io::stdin());
-		let cached = CachedReader::new(reader);
-		readers.push((None, cached));
+		each.add_reader(None, input_format(0), Box::new(std::io::stdin()));
 	}
 
-	let action: Option<Action> = match arg_matches.values_of("command") {
-		Some(ref mut commands) => {
-			let command = match commands.next() {
-				Some(command) => command.to_string(),
-				None => unreachable!(),
+	let delimiters = match arg_matches.value_of("template-delimiters") {
+		Some(delimiters) => match delimiters.split_whitespace().collect::<Vec<&str>>()[..] {
+			[open, close] => Some((open, close)),
+			_ => {
+				return Err(EachError::Usage {
+					message: format!(
+						"Expected an opening and closing delimiter separated by a space: {}",
+						delimiters
+					),
+				})
+			}
+		},
+		None => None,
+	};
+	let template = |template: &str| match delimiters {
+		Some((open, close)) => each::convert_delimiters(template, open, close),
+		None => Ok(template.to_string()),
+	};
+	let output_template = match arg_matches.value_of("output-template") {
+		Some(output_template) => Some(template(output_template)?),
+		None => None,
+	};
+
+	let action: Option<Action> = match exec {
+		true => {
+			// A command file is a whole script, so it's run in shell mode.
+			let shell = arg_matches.is_present("shell") || arg_matches.is_present("command-file");
+			let mut commands = arg_matches.values_of("command").into_iter().flatten();
+			// The command is only a template in shell mode.
+			let command = match arg_matches.value_of("command-file") {
+				Some(command_file) => template(&std::fs::read_to_string(command_file)?)?,
+				// Nothing is run, the template is just printed.
+				None if arg_matches.is_present("map") => {
+					template(arg_matches.value_of("map").unwrap())?
+				}
+				// Each item has its own command, so the command line only has arguments.
+				None if arg_matches.is_present("command-field") => String::new(),
+				None => match commands.next() {
+					Some(command) if shell => template(command)?,
+					Some(command) => command.to_string(),
+					None => unreachable!(),
+				},
 			};
 
 			let stdin = match arg_matches.value_of("stdin") {
-				Some(stdin) => Some(stdin.to_string()),
+				Some(stdin) => Some(template(stdin)?),
 				None => match arg_matches.value_of("stdin-file") {
-					Some(stdin_file) => Some(std::fs::read_to_string(stdin_file)?),
+					Some(stdin_file) => Some(template(&std::fs::read_to_string(stdin_file)?)?),
 					None => None,
 				},
 			};
 
 			let prompt_stdin = arg_matches.is_present("prompt-stdin");
+			if prompt_stdin && stdin.is_none() && !arg_matches.is_present("stdin-field") {
+				return Err(EachError::Usage {
+					message: "--prompt-stdin needs --stdin, --stdin-file or --stdin-field to show"
+						.to_string(),
+				});
+			}
+
+			// Each variable is given as KEY=TEMPLATE, where only the value is a template.
+			let mut env = Vec::new();
+			for var in arg_matches.values_of("env").into_iter().flatten() {
+				match var.split_once('=') {
+					Some((key, value)) if !key.is_empty() => {
+						env.push((key.to_string(), template(value)?))
+					}
+					_ => {
+						return Err(EachError::Usage {
+							message: format!("Invalid env variable, expected KEY=VALUE: {}", var),
+						})
+					}
+				}
+			}
+
+			// Each --arg-file is one more argument, after the command line's, in the order given.
+			let mut args = commands
+				.map(template)
+				.collect::<Result<Vec<String>, EachError>>()?;
+			for arg_file in arg_matches.values_of("arg-file").into_iter().flatten() {
+				args.push(template(&std::fs::read_to_string(arg_file)?)?);
+			}
 
 			match Action::new(
 				command,
 				stdin,
-				commands.map(|c| c.to_string()).collect(),
+				args,
+				env,
+				arg_matches.value_of("cwd").map(template).transpose()?,
 				prompt_stdin || arg_matches.is_present("prompt"),
 				prompt_stdin,
 			) {
-				Ok(action) => Some(action),
+				Ok(mut action) => {
+					action.env_clear = arg_matches.is_present("env-clear");
+					action.set_strict(arg_matches.is_present("strict"));
+					action.keep_going = arg_matches.is_present("keep-going");
+					action.ignore_exit = arg_matches.is_present("ignore-exit");
+					action.dry_run = arg_matches.is_present("dry-run");
+					action.print_command = arg_matches.is_present("print-command");
+					action.stdin_field = arg_matches.value_of("stdin-field").map(str::to_string);
+					action.command_field =
+						arg_matches.value_of("command-field").map(str::to_string);
+					action
+						.set_shell(shell)
+						.and_then(|_| action.set_map(arg_matches.is_present("map")))
+						.map_err(|e| EachError::Usage {
+							message: format!("Invalid template: {:?}", e),
+						})?;
+					action.review = arg_matches.is_present("review");
+					action.progress =
+						arg_matches.is_present("progress") && atty::is(atty::Stream::Stderr);
+					action.color = match arg_matches.value_of("color") {
+						Some("always") => true,
+						Some("never") => false,
+						_ => {
+							std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+								&& atty::is(atty::Stream::Stderr)
+						}
+					};
+					// Prompts and the progress bar style their text with console, which has its own
+					// idea of whether to use color otherwise.
+					dialoguer::console::set_colors_enabled(action.color);
+					dialoguer::console::set_colors_enabled_stderr(action.color);
+					action.collect_json = arg_matches.is_present("collect-json");
+					action.collect = action.collect_json || arg_matches.is_present("collect");
+					// With one process at a time there's nothing to interleave with.
+					action.stream =
+						arg_matches.is_present("stream") || rayon::current_num_threads() == 1;
+					action.timeout = match arg_matches.value_of("timeout") {
+						Some(timeout) => Some(parse_timeout(timeout)?),
+						None => None,
+					};
+					if let Some(deadline) = arg_matches.value_of("deadline") {
+						action.deadline = Some(Instant::now() + parse_timeout(deadline)?);
+					}
+					if let Some(retries) = arg_matches.value_of("retries") {
+						action.retries = parse_count("retries", retries)?;
+					}
+					if let Some(delay) = arg_matches.value_of("retry-delay") {
+						action.retry_delay =
+							Duration::from_millis(parse_count("retry delay", delay)? as u64);
+					}
+					action.retry_backoff = arg_matches.is_present("retry-backoff");
+
+					if let Some(ref template) = output_template {
+						if action.collect {
+							return Err(EachError::Usage {
+								message: "--output-template can't be used with --collect"
+									.to_string(),
+							});
+						}
+
+						let share = arg_matches.is_present("output-append")
+							|| arg_matches.is_present("append");
+						action.append_existing = arg_matches.is_present("append");
+						action
+							.set_output(template, share)
+							.map_err(|e| EachError::Usage {
+								message: format!("Invalid template: {:?}", e),
+							})?;
+					}
+
+					Some(action)
+				}
 				Err(e) => {
 					return Err(EachError::Usage {
 						message: format!("Invalid template: {:?}", e),
@@ -205,96 +748,121 @@ fn run(
 				}
 			}
 		}
-		None => None,
+		false => None,
 	};
 
-	let mut output_values = Vec::new();
-
-	for (ref ext, ref mut reader) in readers.iter_mut() {
-		let format = match arg_matches.value_of("format") {
-			Some(format_id) => formats.get(format_id).ok_or_else(|| EachError::Usage {
-				message: format!("Unknown format: {}", &format_id),
-			})?,
-			None => {
-				formats::guess_format(ext, reader, &formats).ok_or_else(|| EachError::Data {
-					message: "Unable to guess format for input".to_string(),
-				})?
-			}
-		};
-
-		let mut values = format.parse(reader).map_err(|e| EachError::Data {
-			message: format!("failed to parse input: {}", e),
-		})?;
+	each.action = action;
+	for query_str in arg_matches.values_of("query").into_iter().flatten() {
+		each.add_query(query_str)?;
+	}
+	if let Some(query_file) = arg_matches.value_of("query-file") {
+		each.add_query(&std::fs::read_to_string(query_file)?)?;
+	}
+	for spec in arg_matches.values_of("parse-field").into_iter().flatten() {
+		each.add_parse_field(spec)?;
+	}
+	each.flatten = arg_matches.value_of("flatten").map(|field| Flatten {
+		field: field.to_string(),
+		drop_missing: arg_matches.is_present("flatten-drop-missing"),
+	});
+	for expr in arg_matches.values_of("where").into_iter().flatten() {
+		each.add_filter(expr)?;
+	}
+	each.strict_format = arg_matches.is_present("strict-format");
 
-		if let Some(query_str) = arg_matches.value_of("query") {
-			let query = jmespath::compile(query_str).map_err(|e| EachError::Usage {
-				message: format!("Invalid JMES query: {}", e),
-			})?;
+	if arg_matches.is_present("unique") || arg_matches.is_present("unique-by") {
+		each.unique = Some(Unique {
+			field: arg_matches.value_of("unique-by").map(str::to_string),
+		});
+	}
+	each.sort = arg_matches.value_of("sort").map(|field| Sort {
+		field: field.to_string(),
+		desc: arg_matches.is_present("sort-desc"),
+	});
+	if let Some(skip) = arg_matches.value_of("skip") {
+		each.skip = parse_count("skip", skip)?;
+	}
+	if let Some(limit) = arg_matches.value_of("limit") {
+		each.limit = Some(parse_count("limit", limit)?);
+	}
 
-			let query_result = query.search(values).map_err(|e| EachError::Data {
-				message: format!("Error evaluating JMES query: {}", e),
-			})?;
+	if arg_matches.is_present("count") {
+		println!("{}", each.count_inputs()?);
+		return Ok(());
+	}
 
-			values = serde_json::to_value(query_result).map_err(|e| EachError::Data {
-				message: format!("Error converting query result to JSON value: {}", e),
-			})?;
-		}
+	// JSON is the default, unless separating items with null bytes.
+	match arg_matches.value_of("output-format") {
+		Some(format_id) => each.output_format = format_id.to_string(),
+		None if arg_matches.is_present("print0") => each.output_format = PRINT0_FORMAT.to_string(),
+		None => {}
+	}
+	each.output = arg_matches.value_of("output").map(PathBuf::from);
+	each.output_template = output_template;
+	each.output_share = arg_matches.is_present("output-append") || arg_matches.is_present("append");
+	each.append = arg_matches.is_present("append");
+	// Only commands have anything to report.
+	each.stats = exec && arg_matches.is_present("stats");
+
+	each.run_inputs()
+}
 
-		let vec_values = values.as_array().ok_or_else(|| EachError::Data {
-			message: "Input values are not an array".to_string(),
-		})?;
+fn parse_max_procs(max_procs_str: &str) -> Result<usize, EachError> {
+	let invalid = |e: String| EachError::Usage {
+		message: format!("Invalid max-procs: {} ({})", max_procs_str, e),
+	};
 
-		match action {
-			Some(ref action) => process(vec_values, action)?,
-			None => output_values.extend_from_slice(vec_values),
+	// A percentage of the logical CPUs, rounded up so it never drops to zero.
+	if let Some(pct_str) = max_procs_str.strip_suffix('%') {
+		let pct = pct_str.parse::<f64>().map_err(|e| invalid(e.to_string()))?;
+		if !pct.is_finite() || pct < 0.0 {
+			return Err(invalid("percentage must be positive".to_string()));
 		}
+
+		let procs = (num_cpus::get() as f64 * pct / 100.0).ceil() as usize;
+		return Ok(procs.max(1));
 	}
 
-	if action.is_none() {
-		let format = match arg_matches.value_of("output-format") {
-			Some(format_id) => formats.get(format_id).ok_or_else(|| EachError::Usage {
-				message: format!("Unknown output format: {}", &format_id),
-			})?,
-			None => formats.get(DEFAULT_FORMAT).unwrap(),
+	// A multiple of the logical CPUs, e.g. cpus*4 for commands which mostly wait on the network.
+	if let Some(rest) = max_procs_str.strip_prefix("cpus") {
+		let factor = match rest.trim_start().strip_prefix('*') {
+			Some(factor_str) => factor_str
+				.trim()
+				.parse::<f64>()
+				.map_err(|e| invalid(e.to_string()))?,
+			None if rest.is_empty() => 1.0,
+			None => return Err(invalid("expected cpus or cpus*N".to_string())),
 		};
-
-		if let Err(e) = format.write(output_values) {
-			return Err(EachError::Data {
-				message: format!("serialize error: {:?}", e),
-			});
+		if !factor.is_finite() || factor <= 0.0 {
+			return Err(invalid("multiplier must be positive".to_string()));
 		}
+
+		let procs = (num_cpus::get() as f64 * factor).ceil() as usize;
+		return Ok(procs.max(1));
 	}
 
-	Ok(())
+	// Like xargs, 0 means as many as possible, which is one per logical CPU.
+	match max_procs_str.parse::<usize>() {
+		Ok(0) => Ok(num_cpus::get()),
+		Ok(procs) => Ok(procs),
+		Err(e) => Err(invalid(e.to_string())),
+	}
 }
 
-fn process(values: &[serde_json::Value], action: &Action) -> Result<(), EachError> {
-	let results: Result<Vec<()>, EachError> = values
-		.par_iter()
-		.map(|value| -> Result<(), EachError> {
-			let cmd = action.prepare(value).map_err(|e| EachError::Data {
-				message: format!("failed to prepare command: {:?}", e),
-			})?;
-
-			let run = if action.prompt {
-				let prompt = action.prompt(&cmd, value).map_err(|e| EachError::Data {
-					message: format!("failed to render stdin: {:?}", e),
-				})?;
-
-				Confirm::new().with_prompt(&prompt).interact()?
-			} else {
-				true
-			};
-
-			if run {
-				action.run(cmd).map_err(|e| EachError::Data {
-					message: format!("failed to run command: {:?}", e),
-				})?;
-			}
-
-			Ok(())
-		})
-		.collect();
+fn parse_count(name: &str, count_str: &str) -> Result<usize, EachError> {
+	count_str.parse::<usize>().map_err(|e| EachError::Usage {
+		message: format!("Invalid {}: {} ({})", name, count_str, e),
+	})
+}
 
-	results.map(|_| ())
+fn parse_timeout(timeout_str: &str) -> Result<Duration, EachError> {
+	match timeout_str.parse::<f64>() {
+		Ok(secs) if secs.is_finite() && secs > 0.0 => Ok(Duration::from_secs_f64(secs)),
+		_ => Err(EachError::Usage {
+			message: format!(
+				"Invalid timeout: {} (must be a positive number of seconds)",
+				timeout_str
+			),
+		}),
+	}
 }
@@ -1,17 +1,28 @@
 use failure::Error;
+use flate2::read::MultiGzDecoder;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufRead, BufReader, Cursor, Read};
 
+use crate::errors::EachError;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reads a file, transparently decompressing it if it's gzipped.
 pub struct FileReader {
-	reader: BufReader<File>,
+	reader: Box<dyn Read>,
 }
 
 impl FileReader {
 	pub fn new<P: AsRef<std::path::Path>>(path: P) -> Result<Self, Error> {
 		let file = File::open(path)?;
-		Ok(FileReader {
-			reader: BufReader::new(file),
-		})
+		let mut reader = BufReader::new(file);
+
+		let reader: Box<dyn Read> = match reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+			true => Box::new(MultiGzDecoder::new(reader)),
+			false => Box::new(reader),
+		};
+
+		Ok(FileReader { reader })
 	}
 }
 
@@ -21,11 +32,105 @@ impl Read for FileReader {
 	}
 }
 
+/// Reads the body of an HTTP(S) response.
+pub struct UrlReader {
+	reader: Box<dyn Read + Send + Sync>,
+	pub content_type: String,
+}
+
+impl UrlReader {
+	pub fn new(url: &str) -> Result<Self, Error> {
+		let response = match ureq::get(url).call() {
+			Ok(response) => response,
+			Err(ureq::Error::Status(status, _)) => {
+				return Err(EachError::Data {
+					message: format!("HTTP status {}", status),
+				}
+				.into())
+			}
+			Err(e) => return Err(e.into()),
+		};
+
+		Ok(UrlReader {
+			content_type: response.content_type().to_string(),
+			reader: response.into_reader(),
+		})
+	}
+
+	/// The file name at the end of the URL's path, if it looks like it has an extension.
+	pub fn file_name(url: &str) -> Option<String> {
+		let path = url.split(['?', '#']).next()?;
+		let path = path.split_once("://").map_or(path, |(_, rest)| rest);
+
+		// Skip the host, which has dots of its own.
+		let name = path.split_once('/')?.1.rsplit('/').next()?;
+		match name.contains('.') {
+			true => Some(name.to_string()),
+			false => None,
+		}
+	}
+}
+
+impl Read for UrlReader {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+		self.reader.read(buf)
+	}
+}
+
+/// A UTF-8 byte order mark, which some editors and spreadsheets start files with.
+const UTF8_BOM: [u8; 3] = [0xef, 0xbb, 0xbf];
+
+/// Skips a UTF-8 byte order mark at the start of the input, so it doesn't end up in the
+/// first field name or stop the format being recognised.
+pub struct BomReader {
+	reader: Box<dyn Read>,
+	checked: bool,
+}
+
+impl BomReader {
+	pub fn new(reader: Box<dyn Read>) -> Self {
+		BomReader {
+			reader,
+			checked: false,
+		}
+	}
+}
+
+impl Read for BomReader {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+		if !self.checked {
+			self.checked = true;
+
+			// Anything read which turns out not to be a BOM is put back in front.
+			let mut prefix = Vec::with_capacity(UTF8_BOM.len());
+			(&mut self.reader)
+				.take(UTF8_BOM.len() as u64)
+				.read_to_end(&mut prefix)?;
+			if prefix != UTF8_BOM {
+				let reader = std::mem::replace(&mut self.reader, Box::new(std::io::empty()));
+				self.reader = Box::new(Cursor::new(prefix).chain(reader));
+			}
+		}
+
+		self.reader.read(buf)
+	}
+}
+
+/// Wraps an input so its format can be guessed before it's parsed, without any BOM.
+pub fn input_reader(reader: Box<dyn Read>) -> CachedReader {
+	CachedReader::new(Box::new(BomReader::new(reader)))
+}
+
 pub const CACHE_LEN: usize = 4096;
 
+/// Caches the first `CACHE_LEN` bytes read so they can be replayed after a `rewind`, e.g.
+/// to guess the format from a header before parsing from the start. Nothing is read twice from
+/// the underlying reader, so this works for pipes and FIFOs too.
 pub struct CachedReader {
 	buffer: Vec<u8>,
 	index: usize,
+	// Set once bytes past the cache have been read, after which rewinding would lose them.
+	uncached: bool,
 	reader: Box<dyn Read>,
 }
 
@@ -34,12 +139,21 @@ impl CachedReader {
 		CachedReader {
 			buffer: Vec::new(),
 			index: 0,
+			uncached: false,
 			reader,
 		}
 	}
 
-	pub fn rewind(&mut self) {
+	pub fn rewind(&mut self) -> Result<(), std::io::Error> {
+		if self.uncached {
+			return Err(std::io::Error::other(format!(
+				"Can't rewind after reading past the first {} bytes",
+				CACHE_LEN
+			)));
+		}
+
 		self.index = 0;
+		Ok(())
 	}
 }
 
@@ -52,16 +166,16 @@ impl Read for CachedReader {
 			return Ok(to_read);
 		}
 
-		match self.reader.read(buf) {
-			Ok(len) => {
-				if self.index < CACHE_LEN {
-					self.buffer.extend_from_slice(&buf[..len]);
-				}
-
-				self.index += len;
-				Ok(len)
-			}
-			Err(e) => Err(e),
+		if self.buffer.len() < CACHE_LEN {
+			// Read no more than still fits in the cache, so everything read can be replayed.
+			let to_read = std::cmp::min(CACHE_LEN - self.buffer.len(), buf.len());
+			let len = self.reader.read(&mut buf[..to_read])?;
+			self.buffer.extend_from_slice(&buf[..len]);
+			self.index += len;
+			return Ok(len);
 		}
+
+		self.uncached = true;
+		self.reader.read(buf)
 	}
 }
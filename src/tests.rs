@@ -2,13 +2,46 @@
 mod integration {
 	use assert_cli::Assert;
 	use std::collections::HashMap;
+	use std::io::{Read, Write};
 
-	const PEOPLE_CSV_PATH: &'static str = "test-resources/people.csv";
-	const PEOPLE_TSV_PATH: &'static str = "test-resources/people.tsv";
-	const PEOPLE_JSON_PATH: &'static str = "test-resources/people.json";
+	const PEOPLE_CSV_PATH: &str = "test-resources/people.csv";
+	const PEOPLE_CSV_GZ_PATH: &str = "test-resources/people.csv.gz";
+	const PEOPLE_CSV_BOM_PATH: &str = "test-resources/people-bom.csv";
+	const PEOPLE_JSON_BOM_PATH: &str = "test-resources/people-bom.json";
+	const PEOPLE_TSV_PATH: &str = "test-resources/people.tsv";
+	const PEOPLE_JSON_PATH: &str = "test-resources/people.json";
+	const PEOPLE_YAML_PATH: &str = "test-resources/people.yaml";
+	const PEOPLE_TOML_PATH: &str = "test-resources/people.toml";
+	const PEOPLE_NDJSON_PATH: &str = "test-resources/people.ndjson";
+	const PEOPLE_XML_PATH: &str = "test-resources/people.xml";
+	const PEOPLE_FIXED_PATH: &str = "test-resources/people.fixed";
+	const PEOPLE_MSGPACK_PATH: &str = "test-resources/people.msgpack";
+	const PEOPLE_XLSX_PATH: &str = "test-resources/people.xlsx";
+	const PEOPLE_SQLITE_PATH: &str = "test-resources/people.sqlite";
+	const PEOPLE_MARKDOWN_PATH: &str = "test-resources/people.md";
+	const DOTENV_PATH: &str = "test-resources/.env";
+	const SERVICES_INI_PATH: &str = "test-resources/services.ini";
+	const REQUESTS_LOGFMT_PATH: &str = "test-resources/requests.logfmt";
+	const NAMES_PATH: &str = "test-resources/names.txt";
+	const HOMER_QUERY_PATH: &str = "test-resources/homer.jmespath";
+	const GREETING_PATH: &str = "test-resources/greeting.hbs";
+	const GREET_SCRIPT_PATH: &str = "test-resources/greet.sh";
+
+	const FAMILY_JSON: &str = r#"[
+		{"name": "Bart", "age": 10, "status": "active"},
+		{"name": "Homer", "age": 39, "status": "active"},
+		{"name": "Abe", "age": 83},
+		{"name": "Marge", "age": 36, "status": "away"}
+	]"#;
+
+	const TAGGED_JSON: &str = r#"[
+		{"user": "bart", "tags": ["skate", "prank"]},
+		{"user": "homer", "tags": [{"tag": "donut", "count": 3}]},
+		{"user": "marge"}
+	]"#;
 
 	fn norm_newlines<S: Into<String>>(s: S) -> String {
-		return s.into().replace("\r\n", "\n");
+		s.into().replace("\r\n", "\n")
 	}
 
 	fn read_resource(path: &'static str) -> String {
@@ -19,6 +52,42 @@ mod integration {
 		static ref PEOPLE_CSV: String = read_resource(PEOPLE_CSV_PATH);
 		static ref PEOPLE_TSV: String = read_resource(PEOPLE_TSV_PATH);
 		static ref PEOPLE_JSON: String = read_resource(PEOPLE_JSON_PATH);
+		static ref PEOPLE_YAML: String = read_resource(PEOPLE_YAML_PATH);
+		static ref PEOPLE_TOML: String = read_resource(PEOPLE_TOML_PATH);
+		static ref PEOPLE_NDJSON: String = read_resource(PEOPLE_NDJSON_PATH);
+		static ref PEOPLE_XML: String = read_resource(PEOPLE_XML_PATH);
+		static ref PEOPLE_FIXED: String = read_resource(PEOPLE_FIXED_PATH);
+		static ref PEOPLE_MARKDOWN: String = read_resource(PEOPLE_MARKDOWN_PATH);
+		static ref NAMES: String = read_resource(NAMES_PATH);
+	}
+
+	/// Serves a single HTTP response on a local port, returning its URL.
+	fn serve_once(status: &'static str, content_type: &'static str, body: String) -> String {
+		use std::io::{BufRead, BufReader, Write};
+
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}/people", listener.local_addr().unwrap());
+
+		std::thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut request = BufReader::new(stream.try_clone().unwrap());
+			let mut line = String::new();
+			while request.read_line(&mut line).unwrap() > 2 {
+				line.clear();
+			}
+
+			write!(
+				stream,
+				"HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+				status,
+				content_type,
+				body.len(),
+				body
+			)
+			.unwrap();
+		});
+
+		url
 	}
 
 	fn expect_people_json(got_str: &str) -> bool {
@@ -109,7 +178,7 @@ mod integration {
 	#[test]
 	fn call_echo() {
 		Assert::main_binary()
-			.with_args(&["echo", "{{name}} <{{email}}>"])
+			.with_args(&["-P", "1", "echo", "{{name}} <{{email}}>"])
 			.stdin(PEOPLE_CSV.as_str())
 			.succeeds()
 			.and()
@@ -154,6 +223,41 @@ Homer Simpson <homer@example.com>
 			.unwrap();
 	}
 
+	#[test]
+	fn json_to_csv_differing_keys() {
+		Assert::main_binary()
+			.with_args(&["-F", "csv"])
+			.stdin(
+				r#"[
+					{"name": "Bart Simpson"},
+					{"email": "homer@example.com", "name": "Homer Simpson", "town": "Springfield"}
+				]"#,
+			)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== "name,email,town\nBart Simpson,,\nHomer Simpson,homer@example.com,Springfield\n"
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn empty_json_to_csv() {
+		Assert::main_binary()
+			.with_args(&["-f", "json", "-F", "csv"])
+			.stdin("[]")
+			.succeeds()
+			.and()
+			.stdout()
+			.is("")
+			.unwrap();
+	}
+
 	#[test]
 	fn jmes_query() {
 		Assert::main_binary()
@@ -173,4 +277,3577 @@ Homer Simpson <homer@example.com>
 			.is("bart@example.com")
 			.unwrap();
 	}
+
+	#[test]
+	fn jmes_query_chained() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_JSON_PATH,
+				"-q",
+				r#"[?starts_with(name, `"Homer"`)]"#,
+				"-q",
+				"[].{who: name}",
+				"-F",
+				"ndjson",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "{\"who\":\"Homer Simpson\"}\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn jmes_query_per_format() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_JSON_PATH,
+				"-i",
+				PEOPLE_CSV_PATH,
+				"-q",
+				r#"json:[?starts_with(name, `"Bart"`)]"#,
+				"-q",
+				r#"csv:[?starts_with(name, `"Homer"`)]"#,
+				"-q",
+				"[].name",
+				"-F",
+				"ndjson",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "\"Bart Simpson\"\n\"Homer Simpson\"\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn unique_call_echo() {
+		Assert::main_binary()
+			.with_args(&["-P", "1", "-f", "ndjson", "--unique", "echo", "{{name}}"])
+			.stdin(
+				r#"{"name": "Bart Simpson", "email": "bart@example.com"}
+{"name": "Homer Simpson", "email": "homer@example.com"}
+{"email": "bart@example.com", "name": "Bart Simpson"}
+"#,
+			)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Bart Simpson\nHomer Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn unique_by_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"-f",
+				"ndjson",
+				"--unique-by",
+				"email",
+				"echo",
+				"{{name}}",
+			])
+			.stdin(
+				r#"{"name": "Bart Simpson", "email": "bart@example.com"}
+{"name": "Bartholomew Simpson", "email": "bart@example.com"}
+{"name": "Homer Simpson", "email": "homer@example.com"}
+"#,
+			)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Bart Simpson\nHomer Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn sort_by_name() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_JSON_PATH,
+				"--sort",
+				"name",
+				"--sort-desc",
+				"-P",
+				"1",
+				"echo",
+				"{{name}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Homer Simpson\nBart Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn sort_desc_mixed_types() {
+		Assert::main_binary()
+			.with_args(&["--sort", "age", "--sort-desc", "-F", "ndjson"])
+			.stdin(r#"[{"age": "ten"}, {"age": 39}, {}, {"age": 8.5}, {"age": true}]"#)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== "{\"age\":\"ten\"}\n{\"age\":39}\n{\"age\":8.5}\n{\"age\":true}\n{}\n"
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn sort_unique_by() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_JSON_PATH,
+				"-i",
+				PEOPLE_CSV_PATH,
+				"--sort",
+				"name",
+				"--unique-by",
+				"name",
+				"-P",
+				"1",
+				"echo",
+				"{{name}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Bart Simpson\nHomer Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn skip_limit_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"-f", "lines", "--skip", "1", "--limit", "1", "echo", "{{this}}",
+			])
+			.stdin("Bart Simpson\nLisa Simpson\nHomer Simpson\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Lisa Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn skip_limit_across_inputs() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_JSON_PATH,
+				"-i",
+				PEOPLE_CSV_PATH,
+				"--skip",
+				"1",
+				"--limit",
+				"2",
+				"-q",
+				"[].name",
+				"-F",
+				"lines",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Homer Simpson\nBart Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn limit_invalid() {
+		Assert::main_binary()
+			.with_args(&["--limit", "lots", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.fails()
+			.and()
+			.stderr()
+			.contains("Invalid limit")
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_csv_case_insensitive_header() {
+		Assert::main_binary()
+			.with_args(&["-F", "csv", "--csv-header-case-insensitive"])
+			.stdin(
+				r#"[
+					{"name": "Bart Simpson", "email": "bart@example.com"},
+					{"Name": "Homer Simpson", "EMAIL": "homer@example.com"}
+				]"#,
+			)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == norm_newlines(PEOPLE_CSV.as_str()),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_csv_case_insensitive_header_ambiguous() {
+		Assert::main_binary()
+			.with_args(&["-F", "csv", "--csv-header-case-insensitive"])
+			.stdin(r#"[{"name": "Bart Simpson", "Name": "Homer Simpson"}]"#)
+			.fails()
+			.and()
+			.stderr()
+			.contains("differ only by case")
+			.unwrap();
+	}
+
+	#[test]
+	fn max_procs_percentage() {
+		Assert::main_binary()
+			.with_args(&["-P", "50%", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.contains("Bart Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn max_procs_zero() {
+		Assert::main_binary()
+			.with_args(&["-P", "0", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.contains("Bart Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn max_procs_cpus() {
+		Assert::main_binary()
+			.with_args(&["-P", "cpus", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.contains("Bart Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn max_procs_cpus_multiple() {
+		Assert::main_binary()
+			.with_args(&["-P", "cpus*4", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.contains("Homer Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn max_procs_invalid_cpus_multiple() {
+		for max_procs in ["cpus*0", "cpus*lots", "cpus+1"] {
+			Assert::main_binary()
+				.with_args(&["-P", max_procs, "echo", "{{name}}"])
+				.stdin(PEOPLE_CSV.as_str())
+				.fails()
+				.and()
+				.stderr()
+				.contains(format!("Invalid max-procs: {}", max_procs).as_str())
+				.unwrap();
+		}
+	}
+
+	#[test]
+	fn max_procs_invalid_percentage() {
+		Assert::main_binary()
+			.with_args(&["-P", "lots%", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.fails()
+			.and()
+			.stderr()
+			.contains("Invalid max-procs: lots%")
+			.unwrap();
+	}
+
+	#[test]
+	fn records_custom_separator() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"-f",
+				"records",
+				"--record-separator",
+				"---",
+				"echo",
+				"{{this}}",
+			])
+			.stdin("Bart Simpson---Homer Simpson---")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Bart Simpson\nHomer Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn records_keeps_whitespace_record() {
+		Assert::main_binary()
+			.with_args(&["-f", "records", "--record-separator", "---", "--compact"])
+			.stdin("a---b---   ---\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.is(r#"["a","b","   "]"#)
+			.unwrap();
+	}
+
+	#[test]
+	fn records_json_nul_separated() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"-f",
+				"records",
+				"--record-separator",
+				"\\0",
+				"--record-json",
+				"echo",
+				"{{email}}",
+			])
+			.stdin("{\"email\": \"bart@example.com\"}\0{\"email\": \"homer@example.com\"}\0")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "bart@example.com\nhomer@example.com\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn json_indent() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "--indent", "4"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s).trim() == norm_newlines(PEOPLE_JSON.as_str()).trim(),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn json_compact() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "--compact"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					let pretty: serde_json::Value = serde_json::from_str(&PEOPLE_JSON).unwrap();
+					s.trim() == serde_json::to_string(&pretty).unwrap()
+						&& s.trim() != PEOPLE_JSON.trim()
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn json_compact_with_indent_fails() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "--compact", "--indent", "2"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("cannot be used with")
+			.unwrap();
+	}
+
+	#[test]
+	fn strict_checks_first_item_before_running() {
+		Assert::main_binary()
+			.with_args(&["-P", "2", "--strict", "echo", "{{name}}"])
+			.stdin(r#"[{"nmae": "Bart"}, {"name": "Homer"}, {"name": "Marge"}]"#)
+			.fails()
+			.and()
+			.stdout()
+			.is("")
+			.and()
+			.stderr()
+			.contains("Templates don't work with the first item")
+			.unwrap();
+	}
+
+	#[test]
+	fn strict_format_matches() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_JSON_PATH, "-f", "json", "--strict-format"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn strict_format_mismatch() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "-f", "json", "--strict-format"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("Input is not valid json")
+			.unwrap();
+	}
+
+	#[test]
+	fn convert_subcommand() {
+		Assert::main_binary()
+			.with_args(&["convert", "-i", PEOPLE_JSON_PATH, "-F", "csv"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == norm_newlines(PEOPLE_CSV.as_str()),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn convert_subcommand_rejects_exec_args() {
+		Assert::main_binary()
+			.with_args(&["convert", "-i", PEOPLE_JSON_PATH, "-P", "2"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("Found argument '-P'")
+			.unwrap();
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn command_named_convert() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let dir =
+			std::env::temp_dir().join(format!("each-test-{}-convert-cmd", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let script = dir.join("convert");
+		std::fs::write(&script, "#!/bin/sh\necho \"converted $1 to $2\"\n").unwrap();
+		std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+		let path = format!(
+			"{}:{}",
+			dir.display(),
+			std::env::var("PATH").unwrap_or_default()
+		);
+
+		Assert::main_binary()
+			.with_env(assert_cli::Environment::inherit().insert("PATH", path))
+			.with_args(&["--", "convert", "{{name}}", "{{name}}.png"])
+			.stdin(r#"{"name": "bart"}"#)
+			.succeeds()
+			.and()
+			.stdout()
+			.is("converted bart to bart.png")
+			.unwrap();
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn help_lists_subcommands() {
+		Assert::main_binary()
+			.with_args(&["--help"])
+			.succeeds()
+			.and()
+			.stdout()
+			.contains("convert    Convert structured input into another format")
+			.and()
+			.stdout()
+			.contains("run        Execute a command for each item of structured input")
+			.unwrap();
+	}
+
+	#[test]
+	fn run_subcommand_without_options() {
+		Assert::main_binary()
+			.with_args(&["run", "echo", "{{name}}"])
+			.stdin("name\nBart\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Bart")
+			.unwrap();
+	}
+
+	#[test]
+	fn run_subcommand() {
+		Assert::main_binary()
+			.with_args(&[
+				"run",
+				"-P",
+				"1",
+				"-i",
+				PEOPLE_CSV_PATH,
+				"echo",
+				"{{name}} <{{email}}>",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== r#"Bart Simpson <bart@example.com>
+Homer Simpson <homer@example.com>
+"#
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn yaml_explicit_input_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_YAML_PATH])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn yaml_pipe_to_json() {
+		Assert::main_binary()
+			.stdin(PEOPLE_YAML.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn yaml_multi_document_to_json() {
+		Assert::main_binary()
+			.with_args(&["-f", "yaml"])
+			.stdin(
+				r#"name: Bart Simpson
+email: bart@example.com
+---
+name: Homer Simpson
+email: homer@example.com
+"#,
+			)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_yaml() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_JSON_PATH, "-F", "yaml"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == norm_newlines(PEOPLE_YAML.as_str()),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_yaml_indent() {
+		Assert::main_binary()
+			.with_args(&["-F", "yaml", "--indent", "4"])
+			.stdin(
+				r#"[{"name": "Bart", "tags": ["a", "b"], "pets": [{"name": "Santa's Little Helper"}]}]"#,
+			)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== r#"-   name: Bart
+    tags:
+        - a
+        - b
+    pets:
+        -   name: Santa's Little Helper
+"#
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn toml_query_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_TOML_PATH, "-q", "people"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn toml_pipe_wraps_table() {
+		Assert::main_binary()
+			.with_args(&["echo", "{{people.1.name}}"])
+			.stdin(PEOPLE_TOML.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Homer Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn toml_to_toml() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_TOML_PATH, "-F", "toml"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == norm_newlines(PEOPLE_TOML.as_str()),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn ndjson_explicit_input_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_NDJSON_PATH])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn ndjson_pipe_to_json() {
+		Assert::main_binary()
+			.stdin(PEOPLE_NDJSON.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_ndjson() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_JSON_PATH, "-F", "ndjson"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == norm_newlines(PEOPLE_NDJSON.as_str()),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn xml_explicit_input_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_XML_PATH])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn xml_pipe_to_json() {
+		Assert::main_binary()
+			.stdin(PEOPLE_XML.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn xml_record_tag_with_attributes() {
+		Assert::main_binary()
+			.with_args(&["-f", "xml", "--xml-record-tag", "person", "-F", "ndjson"])
+			.stdin(
+				r#"<root>
+					<meta generated="today"/>
+					<person id="1"><name>Bart Simpson</name><tag>a</tag><tag>b</tag></person>
+					<person id="2"><name>Homer Simpson</name></person>
+				</root>"#,
+			)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== r#"{"@id":"1","name":"Bart Simpson","tag":["a","b"]}
+{"@id":"2","name":"Homer Simpson"}
+"#
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_xml_indent() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_JSON_PATH,
+				"-F",
+				"xml",
+				"--xml-record-tag",
+				"person",
+				"--indent",
+				"1",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== r#"<records>
+ <person>
+  <name>Bart Simpson</name>
+  <email>bart@example.com</email>
+ </person>
+ <person>
+  <name>Homer Simpson</name>
+  <email>homer@example.com</email>
+ </person>
+</records>
+"#
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn tsv_extension_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_TSV_PATH])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_tsv() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_JSON_PATH, "-F", "tsv"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == norm_newlines(PEOPLE_TSV.as_str()),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn fixed_to_json() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_FIXED_PATH,
+				"-f",
+				"fixed",
+				"--fixed-columns",
+				"name:0-14,email:14-40",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_fixed() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_JSON_PATH,
+				"-F",
+				"fixed",
+				"--fixed-columns",
+				"name:0-14,email:14-40",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == norm_newlines(PEOPLE_FIXED.as_str()),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn lines_call_echo() {
+		Assert::main_binary()
+			.with_args(&["-P", "1", "-i", NAMES_PATH, "echo", "Hi {{this}}"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Hi Bart Simpson\nHi Homer Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_lines() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_JSON_PATH, "-q", "[*].name", "-F", "lines"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == norm_newlines(NAMES.as_str()),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn msgpack_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_MSGPACK_PATH])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn text_starting_with_msgpack_marker_isnt_msgpack() {
+		// U+0710 is encoded as 0xdc 0x90, where 0xdc is also the msgpack array 16 marker.
+		Assert::main_binary()
+			.with_args(&["--compact"])
+			.stdin("\u{710}\nBart\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.is("[{\"\u{710}\":\"Bart\"}]")
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_msgpack() {
+		// Output is compared lossily since assert_cli only deals in strings.
+		let expected = std::fs::read(PEOPLE_MSGPACK_PATH).unwrap();
+		let expected = String::from_utf8_lossy(&expected).to_string();
+
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_JSON_PATH, "-F", "msgpack"])
+			.succeeds()
+			.and()
+			.stdout()
+			.is(expected.as_str())
+			.unwrap();
+	}
+
+	#[test]
+	fn dotenv_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", DOTENV_PATH, "-F", "ndjson"])
+			.succeeds()
+			.and()
+			.stdout()
+			.is(r#"{"NAME":"Bart Simpson","EMAIL":"bart@example.com"}"#)
+			.unwrap();
+	}
+
+	#[test]
+	fn dotenv_pairs_to_env() {
+		Assert::main_binary()
+			.with_args(&["-i", DOTENV_PATH, "--env-pairs", "-F", "env"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "NAME=\"Bart Simpson\"\nEMAIL=bart@example.com\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn ini_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", SERVICES_INI_PATH, "-F", "ndjson"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== concat!(
+							r#"{"section":null,"log_level":"info"}"#,
+							"\n",
+							r#"{"section":"api","host":"api.example.com","port":"8080"}"#,
+							"\n",
+							r#"{"section":"worker","host":"worker.example.com","queue":"jobs"}"#,
+							"\n",
+						)
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn ini_pipe_guess_format() {
+		Assert::main_binary()
+			.stdin(read_resource(SERVICES_INI_PATH).as_str())
+			.with_args(&["--query", "[?section == 'api'].port", "-F", "ndjson"])
+			.succeeds()
+			.and()
+			.stdout()
+			.is(r#""8080""#)
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_ini() {
+		Assert::main_binary()
+			.stdin(
+				r#"[{"section":null,"debug":true},{"section":"db","host":"localhost","port":5432}]"#,
+			)
+			.with_args(&["-F", "ini"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "debug=true\n\n[db]\nhost=localhost\nport=5432\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn logfmt_pipe_to_json() {
+		Assert::main_binary()
+			.stdin(read_resource(REQUESTS_LOGFMT_PATH).as_str())
+			.with_args(&["-F", "ndjson"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== concat!(
+							r#"{"level":"info","method":"GET","path":"/","status":"200","duration":"12ms"}"#,
+							"\n",
+							r#"{"level":"warn","method":"POST","path":"/login","status":"401","msg":"bad password for \"homer\""}"#,
+							"\n",
+							r#"{"level":"error","method":"GET","path":"/admin","status":"500","msg":"upstream timed out","retry":true}"#,
+							"\n",
+						)
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn logfmt_round_trip() {
+		Assert::main_binary()
+			.with_args(&["-i", REQUESTS_LOGFMT_PATH, "-F", "logfmt"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== read_resource(REQUESTS_LOGFMT_PATH).replace(" retry\n", " retry=true\n")
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn logfmt_unterminated_quote() {
+		Assert::main_binary()
+			.stdin("level=info msg=\"oops\n")
+			.with_args(&["-f", "logfmt", "-F", "ndjson"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("Line 1: Unterminated quoted value for msg")
+			.unwrap();
+	}
+
+	#[test]
+	fn xlsx_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_XLSX_PATH, "-F", "ndjson"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== concat!(
+							r#"{"name":"Bart Simpson","email":"bart@example.com","age":10}"#,
+							"\n",
+							r#"{"name":"Homer Simpson","email":"homer@example.com","age":39.5}"#,
+							"\n",
+						)
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn xlsx_pipe_sheet_call_echo() {
+		Assert::main_binary()
+			.stdin(std::fs::read(PEOPLE_XLSX_PATH).unwrap())
+			.with_args(&[
+				"--xlsx-sheet",
+				"Pets",
+				"-P",
+				"1",
+				"echo",
+				"{{species}}: {{indoor}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "dog: false\ncat: true\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn xlsx_missing_sheet() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_XLSX_PATH, "--xlsx-sheet", "Nope"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("Worksheet 'Nope' not found")
+			.unwrap();
+	}
+
+	#[test]
+	fn sqlite_table_to_json() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_SQLITE_PATH,
+				"--sqlite-table",
+				"people",
+				"-F",
+				"ndjson",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== concat!(
+							r#"{"name":"Bart Simpson","email":"bart@example.com","age":10,"score":null}"#,
+							"\n",
+							r#"{"name":"Homer Simpson","email":"homer@example.com","age":39,"score":2.5}"#,
+							"\n",
+						)
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn sqlite_pipe_query_call_echo() {
+		Assert::main_binary()
+			.stdin(std::fs::read(PEOPLE_SQLITE_PATH).unwrap())
+			.with_args(&[
+				"--sqlite-query",
+				"SELECT email FROM people WHERE age > 30",
+				"echo",
+				"{{email}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("homer@example.com")
+			.unwrap();
+	}
+
+	#[test]
+	fn sqlite_several_tables() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_SQLITE_PATH])
+			.fails()
+			.and()
+			.stderr()
+			.contains("to choose from tables: people, pets")
+			.unwrap();
+	}
+
+	#[test]
+	fn markdown_pipe_to_json() {
+		Assert::main_binary()
+			.stdin(PEOPLE_MARKDOWN.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn empty_json_to_markdown() {
+		Assert::main_binary()
+			.with_args(&["-f", "json", "-F", "markdown"])
+			.stdin("[]")
+			.succeeds()
+			.and()
+			.stdout()
+			.is("")
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_markdown() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_JSON_PATH, "-F", "markdown"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == norm_newlines(PEOPLE_MARKDOWN.as_str()),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn large_json_pipe_to_ndjson() {
+		// Well past the header cache, so guessing has to rewind without losing anything.
+		let count = 25_000;
+		let person = r#"{"name":"Bart Simpson","email":"bart@example.com"}"#;
+		let input = format!("[{}]", vec![person; count].join(","));
+		assert!(input.len() > 1024 * 1024);
+
+		Assert::main_binary()
+			.with_args(&["-F", "ndjson"])
+			.stdin(input)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				move |s| s.lines().count() == count && s.lines().all(|line| line == person),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn object_call_echo() {
+		Assert::main_binary()
+			.with_args(&["echo", "-n", "{{name}}"])
+			.stdin(r#"{"name": "Bart Simpson", "email": "bart@example.com"}"#)
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Bart Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn object_query_call_echo() {
+		Assert::main_binary()
+			.with_args(&["-q", "son", "echo", "-n", "{{name}}"])
+			.stdin(r#"{"name": "Homer Simpson", "son": {"name": "Bart Simpson"}}"#)
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Bart Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn scalar_input_fails() {
+		Assert::main_binary()
+			.with_args(&["-f", "json"])
+			.stdin("42")
+			.fails()
+			.and()
+			.stderr()
+			.contains("must be an array or an object")
+			.unwrap();
+	}
+
+	#[test]
+	fn env_call_sh() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"--env",
+				"GREETING=Hi {{name}}",
+				"sh",
+				"-c",
+				"echo \"$GREETING\"",
+			])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Hi Bart Simpson\nHi Homer Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn env_clear_call_env() {
+		Assert::main_binary()
+			.with_args(&[
+				"-q",
+				"[0]",
+				"--env-clear",
+				"--env",
+				"EMAIL={{email}}",
+				"env",
+			])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.is("EMAIL=bart@example.com")
+			.unwrap();
+	}
+
+	#[test]
+	fn cwd_call_pwd() {
+		Assert::main_binary()
+			.with_args(&["--cwd", "{{dir}}", "pwd"])
+			.stdin(r#"[{"dir": "src"}, {"dir": "test-resources"}]"#)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					let dirs: Vec<&str> = s.lines().collect();
+					dirs.len() == 2
+						&& dirs[0].ends_with("/src")
+						&& dirs[1].ends_with("/test-resources")
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn cwd_missing_fails() {
+		Assert::main_binary()
+			.with_args(&["--cwd", "{{dir}}", "pwd"])
+			.stdin(r#"{"dir": "no-such-dir"}"#)
+			.fails()
+			.and()
+			.stderr()
+			.contains("no-such-dir")
+			.unwrap();
+	}
+
+	#[test]
+	fn shell_call_pipe() {
+		Assert::main_binary()
+			.with_args(&["-P", "1", "--shell", "echo {{name}} | tr a-z A-Z"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "BART SIMPSON\nHOMER SIMPSON\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn timeout_kills_process() {
+		Assert::main_binary()
+			.with_args(&["--timeout", "0.2", "sleep", "{{seconds}}"])
+			.stdin(r#"{"seconds": "10"}"#)
+			.fails()
+			.and()
+			.stderr()
+			.contains("timed out")
+			.unwrap();
+	}
+
+	#[test]
+	fn timeout_kills_process_ignoring_sigterm() {
+		let started = std::time::Instant::now();
+		Assert::main_binary()
+			.with_args(&["--timeout", "0.2", "--shell", "trap '' TERM; exec sleep 10"])
+			.stdin("{}")
+			.fails()
+			.and()
+			.stderr()
+			.contains("timed out")
+			.unwrap();
+		assert!(started.elapsed() < std::time::Duration::from_secs(5));
+	}
+
+	#[test]
+	fn interrupt_stops_starting_commands() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"--shell",
+				"echo {{name}}; kill -INT $PPID; sleep 0.2",
+			])
+			.stdin(PEOPLE_CSV.as_str())
+			.fails_with(130)
+			.and()
+			.stdout()
+			.is("Bart Simpson\n")
+			.and()
+			.stderr()
+			.contains("Interrupted")
+			.unwrap();
+	}
+
+	#[test]
+	fn interrupt_twice_exits() {
+		let started = std::time::Instant::now();
+		// The sleep outlives each, so it mustn't keep the test's pipes open.
+		Assert::main_binary()
+			.with_args(&[
+				"--shell",
+				"exec >/dev/null 2>&1; kill -INT $PPID; sleep 0.2; kill -INT $PPID; sleep 10",
+			])
+			.stdin("{}")
+			.fails_with(130)
+			.unwrap();
+		assert!(started.elapsed() < std::time::Duration::from_secs(5));
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn interrupt_without_command_stops_at_once() {
+		// Nothing's written to the pipe, so each waits to read it until it's interrupted. If it
+		// weren't stopped, the pipe is written to in the end so that the test doesn't hang.
+		let path = std::env::temp_dir().join(format!("each-test-{}-int-fifo", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let made = std::process::Command::new("mkfifo")
+			.arg(&path)
+			.status()
+			.unwrap();
+		assert!(made.success());
+
+		// It's only interrupted once it's started, which waits for cargo to build it.
+		let fifo = path.clone();
+		let pattern = format!("target/debug/each -i {}", path.display());
+		let (sent, interrupted) = std::sync::mpsc::channel();
+		std::thread::spawn(move || {
+			for _ in 0..600 {
+				std::thread::sleep(std::time::Duration::from_millis(100));
+				let killed = std::process::Command::new("pkill")
+					.args(["-INT", "-f", &pattern])
+					.status()
+					.unwrap();
+				if killed.success() {
+					sent.send(std::time::Instant::now()).unwrap();
+					break;
+				}
+			}
+
+			std::thread::sleep(std::time::Duration::from_secs(5));
+			let writer = std::fs::OpenOptions::new()
+				.read(true)
+				.write(true)
+				.open(fifo);
+			let _ = writer.and_then(|mut writer| writer.write_all(b"[]"));
+		});
+
+		Assert::main_binary()
+			.with_args(&["-i", path.to_str().unwrap(), "-f", "json"])
+			.fails()
+			.and()
+			.stderr()
+			.doesnt_contain("waiting for running commands")
+			.unwrap();
+		let interrupted = interrupted.recv().unwrap();
+		assert!(interrupted.elapsed() < std::time::Duration::from_secs(5));
+
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn deadline_kills_process() {
+		let started = std::time::Instant::now();
+		Assert::main_binary()
+			.with_args(&["--deadline", "0.2", "sleep", "{{seconds}}"])
+			.stdin(r#"{"seconds": "10"}"#)
+			.fails()
+			.and()
+			.stderr()
+			.contains("stopped at the deadline")
+			.unwrap();
+		assert!(started.elapsed() < std::time::Duration::from_secs(5));
+	}
+
+	#[test]
+	fn deadline_stops_keep_going() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"--deadline",
+				"0.2",
+				"--keep-going",
+				"sleep",
+				"{{seconds}}",
+			])
+			.stdin(r#"[{"seconds": "10"}, {"seconds": "0"}, {"seconds": "0"}]"#)
+			.fails()
+			.and()
+			.stderr()
+			.contains("Deadline passed before every item was processed")
+			.unwrap();
+	}
+
+	#[test]
+	fn stdin_with_stdin_file() {
+		Assert::main_binary()
+			.with_args(&["--stdin", "{{name}}", "--stdin-file", GREETING_PATH, "cat"])
+			.stdin(PEOPLE_CSV.as_str())
+			.fails()
+			.and()
+			.stderr()
+			.contains("cannot be used with")
+			.unwrap();
+	}
+
+	#[test]
+	fn prompt_stdin_without_stdin() {
+		Assert::main_binary()
+			.with_args(&["--prompt-stdin", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.fails_with(64)
+			.and()
+			.stderr()
+			.contains("--prompt-stdin needs --stdin, --stdin-file or --stdin-field")
+			.unwrap();
+	}
+
+	#[test]
+	fn stdin_field_call_cat() {
+		Assert::main_binary()
+			.with_args(&["-P", "1", "--stdin-field", "payload", "cat"])
+			.stdin(r#"[{"payload": "{{name}} & <b>\n"}, {"payload": {"n": 1}}, {"payload": 2}]"#)
+			.succeeds()
+			.and()
+			.stdout()
+			.is("{{name}} & <b>\n{\"n\":1}2")
+			.unwrap();
+	}
+
+	#[test]
+	fn stdin_field_missing() {
+		Assert::main_binary()
+			.with_args(&["--stdin-field", "payload", "cat"])
+			.stdin(r#"{"name": "Bart"}"#)
+			.fails()
+			.and()
+			.stderr()
+			.contains("Item has no payload field")
+			.unwrap();
+	}
+
+	#[test]
+	fn stdin_timeout_call_cat() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"--timeout",
+				"5",
+				"--stdin",
+				"Hi {{name}}\n",
+				"cat",
+			])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Hi Bart Simpson\nHi Homer Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn json_helper_call_cat() {
+		Assert::main_binary()
+			.with_args(&["-P", "1", "--stdin", "{{json this}}\n", "cat"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					let lines: Vec<String> = norm_newlines(s).lines().map(String::from).collect();
+					expect_people_json(&format!("[{}]", lines.join(",")))
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn case_helpers_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"echo",
+				"{{upper name}}",
+				"{{lower email}}",
+				"{{title \"mr BURNS\"}}",
+			])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== "BART SIMPSON bart@example.com Mr Burns\nHOMER SIMPSON homer@example.com Mr Burns\n"
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn default_helper_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"-f",
+				"ndjson",
+				"echo",
+				"{{default nickname \"none\"}}",
+			])
+			.stdin("{\"nickname\": \"Bart\"}\n{\"nickname\": \"\"}\n{\"nickname\": null}\n{}\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Bart\nnone\nnone\nnone\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn urlencode_helper_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"-f",
+				"lines",
+				"echo",
+				"https://example.com/?q={{urlencode this}}",
+			])
+			.stdin("Bart Simpson & co/~50%\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== "https://example.com/?q=Bart%20Simpson%20%26%20co%2F~50%25\n"
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn shquote_helper_shell() {
+		Assert::main_binary()
+			.with_args(&["-f", "lines", "--shell", "printf '%s\\n' {{shquote this}}"])
+			.stdin("it's $HOME; `ls` \"quoted\"\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "it's $HOME; `ls` \"quoted\"\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn base64_helpers_round_trip() {
+		Assert::main_binary()
+			.with_args(&[
+				"-f",
+				"lines",
+				"-P",
+				"1",
+				"echo",
+				"{{base64encode this}} {{base64decode (base64encode this)}}",
+			])
+			.stdin("Bart Simpson\nÜber €5\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "QmFydCBTaW1wc29u Bart Simpson\nw5xiZXIg4oKsNQ== Über €5\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn base64decode_invalid_fails() {
+		Assert::main_binary()
+			.with_args(&["-f", "lines", "echo", "{{base64decode this}}"])
+			.stdin("not base64!\n")
+			.fails()
+			.and()
+			.stderr()
+			.contains("base64decode")
+			.unwrap();
+	}
+
+	#[test]
+	fn arithmetic_helpers_integers() {
+		Assert::main_binary()
+			.stdin(r#"[{"offset": 7, "page": "2"}]"#)
+			.with_args(&[
+				"-f",
+				"json",
+				"echo",
+				"{{add offset 1}} {{sub offset page}} {{mul offset page}} {{div 8 page}} {{mod offset page}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("8 5 14 4 1")
+			.unwrap();
+	}
+
+	#[test]
+	fn arithmetic_helpers_floats() {
+		Assert::main_binary()
+			.stdin(r#"[{"qty": 3, "price": "2.5"}]"#)
+			.with_args(&[
+				"-f",
+				"json",
+				"echo",
+				"{{mul qty price}} {{add price 1}} {{div qty 2}} {{mod price 1}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("7.5 3.5 1.5 0.5")
+			.unwrap();
+	}
+
+	#[test]
+	fn arithmetic_helper_overflow_fails() {
+		Assert::main_binary()
+			.stdin("{}")
+			.with_args(&["-f", "json", "echo", "{{add 9223372036854775807 1}}"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("add overflowed")
+			.unwrap();
+	}
+
+	#[test]
+	fn arithmetic_helper_not_number_fails() {
+		Assert::main_binary()
+			.stdin(r#"[{"qty": "lots"}]"#)
+			.with_args(&["-f", "json", "echo", "{{add qty 1}}"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("add needs numbers")
+			.unwrap();
+	}
+
+	#[test]
+	fn div_by_zero_fails() {
+		Assert::main_binary()
+			.stdin(r#"[{"qty": 3}]"#)
+			.with_args(&["-f", "json", "echo", "{{div qty 0}}"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("div by zero")
+			.unwrap();
+	}
+
+	#[test]
+	fn date_helper_epoch_and_rfc3339() {
+		Assert::main_binary()
+			.stdin(r#"[{"created": 1700000000, "updated": "2024-02-29T13:45:00+02:00"}]"#)
+			.with_args(&[
+				"-f",
+				"json",
+				"echo",
+				"{{date created \"%Y-%m-%d %H:%M\"}} {{date updated \"%Y%m%d-%H%M%z\"}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("2023-11-14 22:13 20240229-1345+0200")
+			.unwrap();
+	}
+
+	#[test]
+	fn date_helper_negative_fractional_epoch() {
+		Assert::main_binary()
+			.stdin(r#"[{"created": -1.5}]"#)
+			.with_args(&[
+				"-f",
+				"json",
+				"echo",
+				"{{date created \"%Y-%m-%d %H:%M:%S%.3f\"}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("1969-12-31 23:59:58.500")
+			.unwrap();
+	}
+
+	#[test]
+	fn date_helper_invalid_fails() {
+		Assert::main_binary()
+			.stdin(r#"[{"created": "last tuesday"}]"#)
+			.with_args(&["-f", "json", "echo", "{{date created \"%Y\"}}"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("date needs epoch seconds or an RFC 3339 time")
+			.unwrap();
+	}
+
+	#[test]
+	fn template_delimiters_call_echo() {
+		Assert::main_binary()
+			.stdin(r#"[{"name": "web", "port": 8080}]"#)
+			.with_args(&[
+				"-f",
+				"json",
+				"--template-delimiters",
+				"<< >>",
+				"echo",
+				"{{ .Host }}:<<port>>",
+				"<<upper name>>",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("{{ .Host }}:8080 WEB")
+			.unwrap();
+	}
+
+	#[test]
+	fn template_delimiters_unclosed_fails() {
+		Assert::main_binary()
+			.stdin(r#"[{"name": "web"}]"#)
+			.with_args(&[
+				"-f",
+				"json",
+				"--template-delimiters",
+				"<< >>",
+				"echo",
+				"<<name",
+			])
+			.fails()
+			.and()
+			.stderr()
+			.contains("Unclosed << in template")
+			.unwrap();
+	}
+
+	#[test]
+	fn command_file() {
+		Assert::main_binary()
+			.with_args(&["-P", "1", "--command-file", GREET_SCRIPT_PATH])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Hello, Bart Simpson\nHello, Homer Simpson\n")
+			.unwrap();
+	}
+
+	#[test]
+	fn run_command_file() {
+		Assert::main_binary()
+			.with_args(&["run", "-P", "1", "--command-file", GREET_SCRIPT_PATH])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Hello, Bart Simpson\nHello, Homer Simpson\n")
+			.unwrap();
+	}
+
+	#[test]
+	fn command_file_with_command() {
+		Assert::main_binary()
+			.with_args(&["--command-file", GREET_SCRIPT_PATH, "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.fails()
+			.unwrap();
+	}
+
+	#[test]
+	fn map_prints_template() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "--map", "{{name}} {{email}}"])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Bart Simpson bart@example.com\nHomer Simpson homer@example.com\n")
+			.unwrap();
+	}
+
+	#[test]
+	fn map_collect() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_JSON_PATH,
+				"--map",
+				"{{upper name}}",
+				"--collect",
+				"-F",
+				"json",
+				"--compact",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is(r#"["BART SIMPSON","HOMER SIMPSON"]"#)
+			.unwrap();
+	}
+
+	#[test]
+	fn command_field() {
+		Assert::main_binary()
+			.with_args(&["-P", "1", "--command-field", "cmd"])
+			.stdin(
+				r#"[{"cmd": ["echo", "one & two"]}, {"cmd": "printf '%s\n' three | tr a-z A-Z"}]"#,
+			)
+			.succeeds()
+			.and()
+			.stdout()
+			.is("one & two\nTHREE\n")
+			.unwrap();
+	}
+
+	#[test]
+	fn command_field_with_args() {
+		Assert::main_binary()
+			.with_args(&["-P", "1", "--command-field", "cmd", "{{name}}"])
+			.stdin(
+				r#"[{"cmd": ["echo", "Hi"], "name": "Bart"}, {"cmd": "echo Bye", "name": "Homer"}]"#,
+			)
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Hi Bart\nBye Homer\n")
+			.unwrap();
+	}
+
+	#[test]
+	fn command_field_args_not_parsed_by_shell() {
+		Assert::main_binary()
+			.with_args(&["-P", "1", "--command-field", "cmd", "{{name}}"])
+			.stdin(r#"[{"cmd": "printf '%s|\\n'", "name": "a b; echo INJECTED"}]"#)
+			.succeeds()
+			.and()
+			.stdout()
+			.is("a b; echo INJECTED|\n")
+			.unwrap();
+	}
+
+	#[test]
+	fn command_field_invalid() {
+		Assert::main_binary()
+			.with_args(&["--command-field", "cmd"])
+			.stdin(r#"[{"cmd": 42}]"#)
+			.fails()
+			.and()
+			.stderr()
+			.contains("The cmd field must be a command line")
+			.unwrap();
+	}
+
+	#[test]
+	fn arg_file_call_printf() {
+		Assert::main_binary()
+			.with_args(&[
+				"--arg-file",
+				GREETING_PATH,
+				"-P",
+				"1",
+				"printf",
+				"[%s] %s\\n",
+				"{{email}}",
+			])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== "[bart@example.com] Dear Bart Simpson,\nHello!\n\n[homer@example.com] Dear Homer Simpson,\nHello!\n\n"
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn strict_missing_field_fails() {
+		Assert::main_binary()
+			.with_args(&["--strict", "echo", "{{nmae}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.fails()
+			.and()
+			.stderr()
+			.contains("nmae")
+			.unwrap();
+	}
+
+	#[test]
+	fn missing_field_renders_empty() {
+		Assert::main_binary()
+			.with_args(&["echo", "[{{nmae}}]"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(|s| norm_newlines(s) == "[]\n[]\n", "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn index_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"-f",
+				"lines",
+				"-F",
+				"lines",
+				"--collect",
+				"-P",
+				"4",
+				"echo",
+				"{{@index}}-{{this}}",
+			])
+			.stdin((0..20).map(|i| format!("item{}\n", i)).collect::<String>())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== (0..20)
+							.map(|i| format!("{}-item{}\n", i, i))
+							.collect::<String>()
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn review_template_error_fails_before_prompting() {
+		Assert::main_binary()
+			.with_args(&["--review", "--strict", "echo", "{{nmae}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.fails()
+			.and()
+			.stderr()
+			.contains("nmae")
+			.unwrap();
+	}
+
+	#[test]
+	fn progress_hidden_without_terminal() {
+		Assert::main_binary()
+			.with_args(&["--progress", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stderr()
+			.is("")
+			.unwrap();
+	}
+
+	#[test]
+	fn special_characters_not_escaped() {
+		Assert::main_binary()
+			.with_args(&["echo", "{{name}}"])
+			.stdin(r#"[{"name": "A & B <c> \"d\" 'e'"}]"#)
+			.succeeds()
+			.and()
+			.stdout()
+			.is("A & B <c> \"d\" 'e'\n")
+			.unwrap();
+	}
+
+	#[test]
+	fn print_command() {
+		Assert::main_binary()
+			.with_args(&["-P", "1", "--print-command", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Bart Simpson\nHomer Simpson\n")
+			.and()
+			.stderr()
+			.is("echo 'Bart Simpson'\necho 'Homer Simpson'\n")
+			.unwrap();
+	}
+
+	#[test]
+	fn color_always_without_terminal() {
+		Assert::main_binary()
+			.with_args(&["--color", "always", "--progress", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stderr()
+			.is("")
+			.unwrap();
+	}
+
+	#[test]
+	fn color_invalid() {
+		Assert::main_binary()
+			.with_args(&["--color", "sometimes", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.fails()
+			.and()
+			.stderr()
+			.contains("sometimes")
+			.unwrap();
+	}
+
+	#[test]
+	fn stats_reports_counts() {
+		Assert::main_binary()
+			.with_args(&["--stats", "--keep-going", "--cwd", "{{dir}}", "pwd"])
+			.stdin("dir\n/\n/nonexistent-each-test\n")
+			.fails()
+			.and()
+			.stderr()
+			.contains("2 items, 1 commands run, 1 succeeded, 1 failed in ")
+			.unwrap();
+	}
+
+	#[test]
+	fn stats_dry_run_counts_nothing_succeeded() {
+		Assert::main_binary()
+			.with_args(&["--stats", "--keep-going", "-n", "echo", "{{name}}"])
+			.stdin("name\nBart\nHomer\n")
+			.succeeds()
+			.and()
+			.stderr()
+			.contains("2 items, 0 commands run, 0 succeeded, 0 failed in ")
+			.unwrap();
+	}
+
+	#[test]
+	fn failing_command_fails() {
+		Assert::main_binary()
+			.with_args(&["sh", "-c", "exit {{code}}"])
+			.stdin("code\n0\n3\n")
+			.fails()
+			.and()
+			.stderr()
+			.contains("exited with code 3")
+			.unwrap();
+	}
+
+	#[test]
+	fn failing_command_ignore_exit() {
+		Assert::main_binary()
+			.with_args(&["--ignore-exit", "sh", "-c", "exit {{code}}"])
+			.stdin("code\n0\n3\n")
+			.succeeds()
+			.unwrap();
+	}
+
+	#[test]
+	fn keep_going_reports_failures() {
+		Assert::main_binary()
+			.with_args(&["--keep-going", "--cwd", "{{dir}}", "pwd"])
+			.stdin(r#"[{"dir": "src"}, {"dir": "no-such-dir"}, {"dir": "test-resources"}]"#)
+			.fails()
+			.and()
+			.stdout()
+			.satisfies(|s| s.lines().count() == 2, "unexpected output")
+			.and()
+			.stderr()
+			.contains("2 succeeded, 1 failed")
+			.unwrap();
+	}
+
+	#[test]
+	fn command_isnt_a_template_without_shell() {
+		Assert::main_binary()
+			.with_args(&["-n", "./{{odd", "{{name}}"])
+			.stdin("name\nBart\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.is("'./{{odd' Bart")
+			.unwrap();
+	}
+
+	#[test]
+	fn dry_run_prints_commands() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"-n",
+				"--stdin",
+				"Bye {{name}}",
+				"rm",
+				"{{email}}",
+			])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== r#"# Stdin:
+Bye Bart Simpson
+- Command:
+rm 'bart@example.com'
+
+# Stdin:
+Bye Homer Simpson
+- Command:
+rm 'homer@example.com'
+
+"#
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn collect_call_echo() {
+		Assert::main_binary()
+			.with_args(&["--collect", "-F", "ndjson", "echo", "{{name}}"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "\"Bart Simpson\"\n\"Homer Simpson\"\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn logfmt_print0() {
+		Assert::main_binary()
+			.with_args(&["-F", "logfmt", "--print0"])
+			.stdin(r#"[{"name": "Bart"}, {"name": "Homer"}]"#)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(|s| s == "name=Bart\0name=Homer\0", "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn collect_print0_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"--collect",
+				"--print0",
+				"printf",
+				"%s\\n%s",
+				"{{name}}",
+				"{{email}}",
+			])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					s.split('\0').collect::<Vec<_>>()
+						== vec![
+							"Bart Simpson\nbart@example.com",
+							"Homer Simpson\nhomer@example.com",
+							"",
+						]
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn collect_json_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"--collect-json",
+				"echo",
+				r#"{"name": "{{name}}", "email": "{{email}}"}"#,
+			])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn collect_keeps_input_order_in_parallel() {
+		// Earlier items sleep for longer, so they finish last.
+		let count = 100;
+		let input: Vec<serde_json::Value> = (0..count)
+			.map(|i| serde_json::json!({"i": i, "delay": format!("0.{:03}", (count - i) * 2)}))
+			.collect();
+
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"8",
+				"--collect-json",
+				"--shell",
+				"sleep {{delay}}; echo {{i}}",
+			])
+			.stdin(serde_json::to_string(&input).unwrap())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				move |s| {
+					let got: Vec<u64> = serde_json::from_str(s).unwrap();
+					got == (0..count).collect::<Vec<u64>>()
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn stream_shows_output_before_timeout() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"2",
+				"--stream",
+				"--timeout",
+				"0.5",
+				"--shell",
+				"echo {{name}}; sleep 10",
+			])
+			.stdin(PEOPLE_CSV.as_str())
+			.fails()
+			.and()
+			.stdout()
+			.contains("Bart Simpson")
+			.and()
+			.stderr()
+			.contains("timed out")
+			.unwrap();
+	}
+
+	#[test]
+	fn json_to_csv_output_file() {
+		let output_path =
+			std::env::temp_dir().join(format!("each-test-{}.csv", std::process::id()));
+
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_JSON_PATH,
+				"-F",
+				"csv",
+				"-o",
+				output_path.to_str().unwrap(),
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("")
+			.unwrap();
+
+		let output = std::fs::read_to_string(&output_path).unwrap();
+		std::fs::remove_file(&output_path).unwrap();
+		assert_eq!(norm_newlines(output), norm_newlines(PEOPLE_CSV.as_str()));
+	}
+
+	#[test]
+	fn csv_to_gzipped_json_output_file() {
+		let output_path =
+			std::env::temp_dir().join(format!("each-test-{}.json.gz", std::process::id()));
+
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "-o", output_path.to_str().unwrap()])
+			.succeeds()
+			.unwrap();
+
+		let output = std::fs::read(&output_path).unwrap();
+		assert!(output.starts_with(&[0x1f, 0x8b]));
+
+		Assert::main_binary()
+			.with_args(&["-i", output_path.to_str().unwrap()])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+		std::fs::remove_file(&output_path).unwrap();
+	}
+
+	#[test]
+	fn csv_to_zstd_csv_output_file() {
+		let output_path =
+			std::env::temp_dir().join(format!("each-test-{}.csv.zst", std::process::id()));
+
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_CSV_PATH,
+				"-F",
+				"csv",
+				"-o",
+				output_path.to_str().unwrap(),
+			])
+			.succeeds()
+			.unwrap();
+
+		let output = zstd::decode_all(std::fs::File::open(&output_path).unwrap()).unwrap();
+		std::fs::remove_file(&output_path).unwrap();
+		assert_eq!(
+			norm_newlines(String::from_utf8(output).unwrap()),
+			norm_newlines(PEOPLE_CSV.as_str())
+		);
+	}
+
+	#[test]
+	fn gzipped_csv_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_GZ_PATH])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn url_csv_to_json() {
+		let url = serve_once("200 OK", "text/csv; charset=utf-8", PEOPLE_CSV.to_string());
+
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				&url,
+				"-i",
+				PEOPLE_JSON_PATH,
+				"-q",
+				"[0]",
+				"-F",
+				"ndjson",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| s.lines().count() == 2 && s.lines().all(|line| line.contains("Bart Simpson")),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn url_not_found_fails() {
+		let url = serve_once("404 Not Found", "text/plain", "Not found".to_string());
+
+		Assert::main_binary()
+			.with_args(&["-i", &url])
+			.fails()
+			.and()
+			.stderr()
+			.contains("HTTP status 404")
+			.unwrap();
+	}
+
+	#[test]
+	fn glob_inputs_to_json() {
+		// Matches people.json and people.ndjson, in that order.
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				"test-resources/people.*json",
+				"-q",
+				"[*].name",
+				"-F",
+				"lines",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s) == "Bart Simpson\nHomer Simpson\nBart Simpson\nHomer Simpson\n"
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn glob_no_matches_fails() {
+		Assert::main_binary()
+			.with_args(&["-i", "test-resources/*.nothing"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("No files match test-resources/*.nothing")
+			.unwrap();
+	}
+
+	#[test]
+	fn directory_inputs_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", "test-resources/tree", "-q", "[*].name", "-F", "lines"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Bart Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn recursive_directory_inputs_to_json() {
+		Assert::main_binary()
+			.with_args(&[
+				"-r",
+				"-i",
+				"test-resources/tree",
+				"-q",
+				"[*].name",
+				"-F",
+				"lines",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Bart Simpson\nHomer Simpson\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn csv_infer_types_to_json() {
+		Assert::main_binary()
+			.with_args(&["--csv-infer-types", "-q", "[?age > `9`]", "-F", "ndjson"])
+			.stdin("name,age,height,student,email\nBart Simpson,10,1.2,true,\nMaggie Simpson,1,0.7,false,\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.is(r#"{"age":10,"email":null,"height":1.2,"name":"Bart Simpson","student":true}"#)
+			.unwrap();
+	}
+
+	#[test]
+	fn csv_no_headers_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"-P",
+				"1",
+				"-f",
+				"csv",
+				"--csv-no-headers",
+				"echo",
+				"{{col1}}",
+			])
+			.stdin("Bart Simpson,bart@example.com\nHomer Simpson,homer@example.com\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "bart@example.com\nhomer@example.com\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn csv_comment_to_json() {
+		Assert::main_binary()
+			.with_args(&["--csv-comment", "#"])
+			.stdin(format!(
+				"# Exported from the Springfield directory\n{}",
+				PEOPLE_CSV.as_str()
+			))
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn csv_trim_to_json() {
+		Assert::main_binary()
+			.with_args(&["--csv-trim"])
+			.stdin("name , email\n Bart Simpson , bart@example.com\nHomer Simpson,  homer@example.com  \n")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn csv_flexible_to_json() {
+		Assert::main_binary()
+			.with_args(&["-f", "csv", "--csv-flexible", "-F", "ndjson"])
+			.stdin("name,email\nBart Simpson\nHomer Simpson,homer@example.com,Springfield\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== r#"{"name":"Bart Simpson","email":null}
+{"name":"Homer Simpson","email":"homer@example.com","_extra":["Springfield"]}
+"#
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn csv_ragged_fails() {
+		Assert::main_binary()
+			.with_args(&["-f", "csv"])
+			.stdin("name,email\nBart Simpson,bart@example.com\nHomer Simpson\n")
+			.fails()
+			.and()
+			.stderr()
+			.contains("Row 1 has different number of records than the header")
+			.unwrap();
+	}
+
+	#[test]
+	fn output_template_per_command() {
+		let dir =
+			std::env::temp_dir().join(format!("each-test-{}-per-command", std::process::id()));
+		let template = format!("{}/{{{{name}}}}.txt", dir.to_str().unwrap());
+
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_CSV_PATH,
+				"--output-template",
+				&template,
+				"echo",
+				"{{email}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("")
+			.unwrap();
+
+		let bart = std::fs::read_to_string(dir.join("Bart Simpson.txt")).unwrap();
+		let homer = std::fs::read_to_string(dir.join("Homer Simpson.txt")).unwrap();
+		std::fs::remove_dir_all(&dir).unwrap();
+		assert_eq!(bart, "bart@example.com\n");
+		assert_eq!(homer, "homer@example.com\n");
+	}
+
+	#[test]
+	fn output_template_gzips_command_output() {
+		let dir = std::env::temp_dir().join(format!("each-test-{}-gz-out", std::process::id()));
+		let template = format!("{}/{{{{name}}}}.txt.gz", dir.display());
+
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_CSV_PATH,
+				"--output-template",
+				&template,
+				"echo",
+				"{{email}}",
+			])
+			.succeeds()
+			.unwrap();
+
+		let mut bart = String::new();
+		flate2::read::MultiGzDecoder::new(
+			std::fs::File::open(dir.join("Bart Simpson.txt.gz")).unwrap(),
+		)
+		.read_to_string(&mut bart)
+		.unwrap();
+		std::fs::remove_dir_all(&dir).unwrap();
+		assert_eq!(bart, "bart@example.com\n");
+	}
+
+	#[test]
+	fn output_template_collision_fails() {
+		let path =
+			std::env::temp_dir().join(format!("each-test-{}-collision.txt", std::process::id()));
+
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_CSV_PATH,
+				"--output-template",
+				path.to_str().unwrap(),
+				"echo",
+				"{{email}}",
+			])
+			.fails()
+			.and()
+			.stderr()
+			.contains("is used by more than one item")
+			.unwrap();
+
+		let _ = std::fs::remove_file(&path);
+	}
+
+	#[test]
+	fn output_template_append() {
+		let path =
+			std::env::temp_dir().join(format!("each-test-{}-append.txt", std::process::id()));
+
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_CSV_PATH,
+				"-P",
+				"1",
+				"--output-template",
+				path.to_str().unwrap(),
+				"--output-append",
+				"echo",
+				"{{email}}",
+			])
+			.succeeds()
+			.unwrap();
+
+		let output = std::fs::read_to_string(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(output, "bart@example.com\nhomer@example.com\n");
+	}
+
+	#[test]
+	fn output_template_append_existing() {
+		let path = std::env::temp_dir().join(format!(
+			"each-test-{}-append-existing.txt",
+			std::process::id()
+		));
+		std::fs::write(&path, "lisa@example.com\n").unwrap();
+
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_CSV_PATH,
+				"-P",
+				"1",
+				"--output-template",
+				path.to_str().unwrap(),
+				"--append",
+				"echo",
+				"{{email}}",
+			])
+			.succeeds()
+			.unwrap();
+
+		let output = std::fs::read_to_string(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(
+			output,
+			"lisa@example.com\nbart@example.com\nhomer@example.com\n"
+		);
+	}
+
+	#[test]
+	fn output_file_append() {
+		let path =
+			std::env::temp_dir().join(format!("each-test-{}-append.csv", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+
+		for _ in 0..2 {
+			Assert::main_binary()
+				.with_args(&[
+					"-i",
+					PEOPLE_CSV_PATH,
+					"-F",
+					"csv",
+					"-o",
+					path.to_str().unwrap(),
+					"--append",
+				])
+				.succeeds()
+				.unwrap();
+		}
+
+		// Each run writes a whole CSV file, header and all.
+		let output = std::fs::read_to_string(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(norm_newlines(output), norm_newlines(PEOPLE_CSV.repeat(2)));
+	}
+
+	#[test]
+	fn output_template_without_command() {
+		let dir = std::env::temp_dir().join(format!("each-test-{}-convert", std::process::id()));
+		let template = format!("{}/{{{{name}}}}.json", dir.to_str().unwrap());
+
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "--output-template", &template])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("")
+			.unwrap();
+
+		let bart = std::fs::read_to_string(dir.join("Bart Simpson.json")).unwrap();
+		std::fs::remove_dir_all(&dir).unwrap();
+		let bart: serde_json::Value = serde_json::from_str(&bart).unwrap();
+		assert_eq!(
+			bart,
+			serde_json::json!([{"name": "Bart Simpson", "email": "bart@example.com"}])
+		);
+	}
+
+	#[test]
+	fn output_template_index_without_command() {
+		let dir = std::env::temp_dir().join(format!("each-test-{}-conv-index", std::process::id()));
+		let template = format!("{}/conv-{{{{@index}}}}.json.gz", dir.to_str().unwrap());
+
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "--output-template", &template])
+			.succeeds()
+			.unwrap();
+
+		let mut homer = String::new();
+		flate2::read::MultiGzDecoder::new(std::fs::File::open(dir.join("conv-1.json.gz")).unwrap())
+			.read_to_string(&mut homer)
+			.unwrap();
+		std::fs::remove_dir_all(&dir).unwrap();
+		let homer: serde_json::Value = serde_json::from_str(&homer).unwrap();
+		assert_eq!(
+			homer,
+			serde_json::json!([{"name": "Homer Simpson", "email": "homer@example.com"}])
+		);
+	}
+
+	#[test]
+	fn retries_failed_command() {
+		let marker = std::env::temp_dir().join(format!("each-test-{}-retry", std::process::id()));
+		let command = format!(
+			"test -e {0} || {{ touch {0}; exit 1; }}; echo {{{{name}}}}",
+			marker.to_str().unwrap()
+		);
+
+		Assert::main_binary()
+			.with_env(assert_cli::Environment::inherit().insert("RUST_LOG", "info"))
+			.with_args(&["--retries", "2", "--retry-delay", "10", "--shell", &command])
+			.stdin("{\"name\": \"Bart Simpson\"}")
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Bart Simpson")
+			.and()
+			.stderr()
+			.contains("Retrying (1/2)")
+			.unwrap();
+
+		std::fs::remove_file(&marker).unwrap();
+	}
+
+	#[test]
+	fn retries_run_out() {
+		Assert::main_binary()
+			.with_args(&["--retries", "1", "--shell", "echo attempt >&2; exit 1"])
+			.stdin("{}")
+			.fails()
+			.and()
+			.stderr()
+			.satisfies(
+				|s| norm_newlines(s).starts_with("attempt\nattempt\nError"),
+				"expected two attempts",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn ndjson_limit_stops_reading() {
+		// The bad line is never parsed.
+		Assert::main_binary()
+			.with_args(&["-f", "ndjson", "--limit", "1", "echo", "{{name}}"])
+			.stdin("{\"name\": \"Bart Simpson\"}\nnot json\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Bart Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn ndjson_batches_keep_numbering() {
+		let input: String = (0..2500).map(|i| format!("{{\"id\": {}}}\n", i)).collect();
+
+		Assert::main_binary()
+			.with_args(&[
+				"-f",
+				"ndjson",
+				"-P",
+				"1",
+				"--skip",
+				"1000",
+				"echo",
+				"{{@index}}:{{id}}",
+			])
+			.stdin(input)
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					let lines: Vec<&str> = s.lines().collect();
+					lines.len() == 1500 && lines[0] == "0:1000" && lines[1499] == "1499:2499"
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn streamed_and_whole_inputs_together() {
+		// NDJSON is parsed a line at a time, JSON whole and then split into its items.
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_NDJSON_PATH,
+				"-i",
+				PEOPLE_JSON_PATH,
+				"-P",
+				"1",
+				"echo",
+				"{{@index}}",
+				"{{name}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== "0 Bart Simpson\n1 Homer Simpson\n2 Bart Simpson\n3 Homer Simpson\n"
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn guess_json_with_bom() {
+		// The query only applies if the input is read as JSON.
+		Assert::main_binary()
+			.with_args(&["-q", "json:[1]", "-P", "1", "echo", "{{name}}"])
+			.stdin(format!("\u{feff}{}", PEOPLE_JSON.as_str()))
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Homer Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn guess_json_with_leading_spaces() {
+		Assert::main_binary()
+			.with_args(&["-q", "json:[1]", "-P", "1", "echo", "{{name}}"])
+			.stdin(format!("  \n  {}", PEOPLE_JSON.as_str()))
+			.succeeds()
+			.and()
+			.stdout()
+			.is("Homer Simpson")
+			.unwrap();
+	}
+
+	#[test]
+	fn csv_with_bom_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_BOM_PATH])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn json_with_bom_to_json() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_JSON_BOM_PATH])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn format_per_input() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_CSV_PATH,
+				"-i",
+				PEOPLE_CSV_PATH,
+				"-f",
+				"lines,csv",
+				"-F",
+				"ndjson",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== r#""name,email"
+"Bart Simpson,bart@example.com"
+"Homer Simpson,homer@example.com"
+{"name":"Bart Simpson","email":"bart@example.com"}
+{"name":"Homer Simpson","email":"homer@example.com"}
+"#
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn format_per_input_guesses_the_rest() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_CSV_PATH,
+				"-f",
+				"lines",
+				"-i",
+				PEOPLE_CSV_PATH,
+				"-f",
+				"lines",
+				"-i",
+				PEOPLE_JSON_PATH,
+				"-F",
+				"ndjson",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s).ends_with(
+						r#""Homer Simpson,homer@example.com"
+{"name":"Bart Simpson","email":"bart@example.com"}
+{"name":"Homer Simpson","email":"homer@example.com"}
+"#,
+					)
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn more_formats_than_inputs_fails() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "-f", "csv,json"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("2 formats given for 1 inputs")
+			.unwrap();
+	}
+
+	#[test]
+	fn ndjson_output_streams() {
+		// Values are written a batch at a time, so those read before the bad line are output.
+		let mut input: String = (0..1100).map(|i| format!("{{\"id\": {}}}\n", i)).collect();
+		input.push_str("not json\n");
+
+		Assert::main_binary()
+			.with_args(&["-f", "ndjson", "-F", "ndjson"])
+			.stdin(input)
+			.fails()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| s.lines().count() == 1024 && s.starts_with("{\"id\":0}\n"),
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn count_people() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "--count"])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("2")
+			.unwrap();
+	}
+
+	#[test]
+	fn count_skips_command() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "--count", "--skip", "1", "false"])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("1")
+			.unwrap();
+	}
+
+	#[test]
+	fn library_parse_and_run() {
+		let mut each = each::Each::new();
+		each.add_query("[?name == 'Homer Simpson']").unwrap();
+
+		let file = std::fs::File::open(PEOPLE_CSV_PATH).unwrap();
+		let values = each
+			.parse_input(Some("people.csv"), None, Box::new(file))
+			.unwrap();
+
+		let mut action = each::Action::new(
+			"echo".to_string(),
+			None,
+			vec!["{{email}}".to_string()],
+			Vec::new(),
+			None,
+			false,
+			false,
+		)
+		.unwrap();
+		action.collect = true;
+		each.action = Some(action);
+
+		assert_eq!(
+			each.run(&values).unwrap(),
+			vec![serde_json::json!("homer@example.com")]
+		);
+	}
+
+	#[test]
+	fn stdin_collect_json_call_cat() {
+		Assert::main_binary()
+			.with_args(&["--stdin", "{{json this}}", "--collect-json", "cat"])
+			.stdin(PEOPLE_CSV.as_str())
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(expect_people_json, "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn query_file_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"-i",
+				PEOPLE_CSV_PATH,
+				"-Q",
+				HOMER_QUERY_PATH,
+				"echo",
+				"{{email}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("homer@example.com")
+			.unwrap();
+	}
+
+	#[test]
+	fn query_file_with_query_fails() {
+		Assert::main_binary()
+			.with_args(&["-i", PEOPLE_CSV_PATH, "-Q", HOMER_QUERY_PATH, "-q", "[0]"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("cannot be used with")
+			.unwrap();
+	}
+
+	#[test]
+	fn where_number() {
+		Assert::main_binary()
+			.stdin(FAMILY_JSON)
+			.with_args(&[
+				"-f", "json", "-w", "age > 30", "-P", "1", "echo", "{{name}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "Homer\nAbe\nMarge\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn where_string_and_number() {
+		Assert::main_binary()
+			.stdin(FAMILY_JSON)
+			.with_args(&[
+				"-f",
+				"json",
+				"--where",
+				"status == 'active' && age >= 39 || name == \"Bart\"",
+				"-P",
+				"1",
+				"echo",
+				"{{name}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(|s| norm_newlines(s) == "Bart\nHomer\n", "unexpected output")
+			.unwrap();
+	}
+
+	#[test]
+	fn where_missing_field() {
+		Assert::main_binary()
+			.stdin(FAMILY_JSON)
+			.with_args(&["-f", "json", "-w", "status != \"active\"", "-F", "ndjson"])
+			.succeeds()
+			.and()
+			.stdout()
+			.is(r#"{"name":"Marge","age":36,"status":"away"}"#)
+			.unwrap();
+	}
+
+	#[test]
+	fn where_invalid() {
+		Assert::main_binary()
+			.stdin(FAMILY_JSON)
+			.with_args(&["-f", "json", "-w", "age = 10"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("Invalid --where expression")
+			.unwrap();
+	}
+
+	#[test]
+	fn flatten_keeps_missing() {
+		Assert::main_binary()
+			.stdin(TAGGED_JSON)
+			.with_args(&["-f", "json", "--flatten", "tags", "-F", "ndjson"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== concat!(
+							r#"{"user":"bart","value":"skate"}"#,
+							"\n",
+							r#"{"user":"bart","value":"prank"}"#,
+							"\n",
+							r#"{"user":"homer","tag":"donut","count":3}"#,
+							"\n",
+							r#"{"user":"marge"}"#,
+							"\n",
+						)
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn flatten_drop_missing_call_echo() {
+		Assert::main_binary()
+			.stdin(TAGGED_JSON)
+			.with_args(&[
+				"-f",
+				"json",
+				"--flatten",
+				"tags",
+				"--flatten-drop-missing",
+				"-P",
+				"1",
+				"echo",
+				"{{user}}: {{value}}{{tag}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "bart: skate\nbart: prank\nhomer: donut\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn parse_field_json_call_echo() {
+		Assert::main_binary()
+			.stdin(r#"[{"id": 1, "payload": "{\"name\": \"Bart\", \"age\": 10}"}, {"id": 2}]"#)
+			.with_args(&[
+				"--parse-field",
+				"payload:json",
+				"-P",
+				"1",
+				"echo",
+				"{{id}} {{payload.name}} {{payload.age}}",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.is("1 Bart 10\n2\n")
+			.unwrap();
+	}
+
+	#[test]
+	fn parse_field_csv_then_flatten() {
+		Assert::main_binary()
+			.stdin(r#"{"team": "Simpsons", "members": "name,age\nBart,10\nLisa,8\n"}"#)
+			.with_args(&[
+				"-f",
+				"json",
+				"--parse-field",
+				"members:csv",
+				"--flatten",
+				"members",
+				"-F",
+				"ndjson",
+			])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== concat!(
+							r#"{"team":"Simpsons","name":"Bart","age":"10"}"#,
+							"\n",
+							r#"{"team":"Simpsons","name":"Lisa","age":"8"}"#,
+							"\n",
+						)
+				},
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn parse_field_invalid() {
+		Assert::main_binary()
+			.stdin(r#"{"payload": "{not json"}"#)
+			.with_args(&["-f", "json", "--parse-field", "payload:json"])
+			.fails()
+			.and()
+			.stderr()
+			.contains("Couldn't parse field payload as json")
+			.unwrap();
+	}
+
+	/// A named pipe which `data` is written to once it's opened for reading.
+	#[cfg(unix)]
+	fn fifo_with(name: &str, data: &'static str) -> std::path::PathBuf {
+		let path = std::env::temp_dir().join(format!("each-test-{}-{}", std::process::id(), name));
+		let _ = std::fs::remove_file(&path);
+		let made = std::process::Command::new("mkfifo")
+			.arg(&path)
+			.status()
+			.unwrap();
+		assert!(made.success());
+
+		let writer_path = path.clone();
+		std::thread::spawn(move || std::fs::write(writer_path, data).unwrap());
+		path
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn fifo_with_format_to_json() {
+		let path = fifo_with("fifo.json", &PEOPLE_JSON);
+
+		Assert::main_binary()
+			.with_args(&["-i", path.to_str().unwrap(), "-f", "json", "-F", "ndjson"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| {
+					norm_newlines(s)
+						== concat!(
+							r#"{"name":"Bart Simpson","email":"bart@example.com"}"#,
+							"\n",
+							r#"{"name":"Homer Simpson","email":"homer@example.com"}"#,
+							"\n",
+						)
+				},
+				"unexpected output",
+			)
+			.unwrap();
+
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn fifo_guess_format_call_echo() {
+		// No extension, so the format is guessed from the start of the data, which is then
+		// replayed for parsing rather than read from the pipe again.
+		let path = fifo_with("fifo", &PEOPLE_CSV);
+
+		Assert::main_binary()
+			.with_args(&["-i", path.to_str().unwrap(), "-P", "1", "echo", "{{email}}"])
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "bart@example.com\nhomer@example.com\n",
+				"unexpected output",
+			)
+			.unwrap();
+
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn first_last_total_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"-f",
+				"lines",
+				"-P",
+				"1",
+				"echo",
+				"{{#if @first}}[{{/if}}{{this}} {{@index}}/{{@total}}{{#if @last}}]{{else}},{{/if}}",
+			])
+			.stdin("a\nb\nc\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				|s| norm_newlines(s) == "[a 0/3,\nb 1/3,\nc 2/3]\n",
+				"unexpected output",
+			)
+			.unwrap();
+	}
+
+	#[test]
+	fn last_after_limit_call_echo() {
+		Assert::main_binary()
+			.with_args(&[
+				"-f",
+				"lines",
+				"--skip",
+				"1",
+				"--limit",
+				"2",
+				"-P",
+				"1",
+				"echo",
+				"{{this}}{{#if @last}}.{{/if}}",
+			])
+			.stdin("a\nb\nc\nd\n")
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(|s| norm_newlines(s) == "b\nc.\n", "unexpected output")
+			.unwrap();
+	}
+
+	/// Runs each and returns its stdout, failing the test if it fails.
+	fn output_of(args: &[&str], stdin: Option<&str>) -> String {
+		let output = std::sync::Arc::new(std::sync::Mutex::new(String::new()));
+		let captured = output.clone();
+
+		let mut assert = Assert::main_binary().with_args(args);
+		if let Some(stdin) = stdin {
+			assert = assert.stdin(stdin);
+		}
+		assert
+			.succeeds()
+			.and()
+			.stdout()
+			.satisfies(
+				move |s| {
+					*captured.lock().unwrap() = s.to_string();
+					true
+				},
+				"",
+			)
+			.unwrap();
+
+		let output = output.lock().unwrap();
+		output.clone()
+	}
+
+	/// Reads `path` as `format`, writes it back out in the same format and checks that reading
+	/// that gives the same items.
+	fn assert_round_trip(format: &str, path: &str) {
+		let items = output_of(&["-i", path, "-f", format, "-F", "json", "--compact"], None);
+		let written = output_of(&["-i", path, "-f", format, "-F", format], None);
+		let reread = output_of(
+			&["-f", format, "-F", "json", "--compact"],
+			Some(written.as_str()),
+		);
+
+		let items: serde_json::Value = serde_json::from_str(&items).unwrap();
+		let reread: serde_json::Value = serde_json::from_str(&reread).unwrap();
+		assert_eq!(
+			reread, items,
+			"{} didn't round trip, it was written as:\n{}",
+			format, written
+		);
+	}
+
+	/// Writable formats with a file to read and write back, which should come out the same.
+	/// Logfmt reads bare keys as `true`, which is written back as a string, and fixed width
+	/// needs its columns given, so they aren't here.
+	const ROUND_TRIPS: [(&str, &str); 9] = [
+		("json", PEOPLE_JSON_PATH),
+		("csv", PEOPLE_CSV_PATH),
+		("tsv", PEOPLE_TSV_PATH),
+		("yaml", PEOPLE_YAML_PATH),
+		("ndjson", PEOPLE_NDJSON_PATH),
+		("toml", PEOPLE_TOML_PATH),
+		("xml", PEOPLE_XML_PATH),
+		("ini", SERVICES_INI_PATH),
+		("markdown", PEOPLE_MARKDOWN_PATH),
+	];
+
+	#[test]
+	fn formats_round_trip() {
+		for (format, path) in ROUND_TRIPS {
+			assert_round_trip(format, path);
+		}
+	}
 }
@@ -0,0 +1,82 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Stdout, Write};
+use std::path::Path;
+
+/// Somewhere to write output to, which must be `finish`ed so that any compressed stream is
+/// completed and errors doing so aren't lost.
+pub struct Output(Inner);
+
+enum Inner {
+	Plain(BufWriter<File>),
+	Gzip(BufWriter<GzEncoder<File>>),
+	Zstd(BufWriter<zstd::Encoder<'static, File>>),
+	Stdout(BufWriter<Stdout>),
+}
+
+impl Output {
+	/// Writes to stdout. It's not locked, since commands may be writing to it from other
+	/// threads meanwhile.
+	pub fn stdout() -> Output {
+		Output(Inner::Stdout(BufWriter::new(std::io::stdout())))
+	}
+
+	/// Flushes everything written and completes any compressed stream.
+	pub fn finish(self) -> Result<(), std::io::Error> {
+		match self.0 {
+			Inner::Plain(writer) => writer.into_inner().map_err(|e| e.into_error())?.flush(),
+			Inner::Gzip(writer) => writer
+				.into_inner()
+				.map_err(|e| e.into_error())?
+				.finish()
+				.map(|_| ()),
+			Inner::Zstd(writer) => writer
+				.into_inner()
+				.map_err(|e| e.into_error())?
+				.finish()
+				.map(|_| ()),
+			Inner::Stdout(mut writer) => writer.flush(),
+		}
+	}
+
+	fn writer(&mut self) -> &mut dyn Write {
+		match self.0 {
+			Inner::Plain(ref mut writer) => writer,
+			Inner::Gzip(ref mut writer) => writer,
+			Inner::Zstd(ref mut writer) => writer,
+			Inner::Stdout(ref mut writer) => writer,
+		}
+	}
+}
+
+impl Write for Output {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+		self.writer().write(buf)
+	}
+
+	fn flush(&mut self) -> Result<(), std::io::Error> {
+		self.writer().flush()
+	}
+}
+
+/// Creates a file to write to, or adds to the end of it if `append` is set, transparently
+/// compressing what's written if its name ends in `.gz` (gzip) or `.zst` (zstd). Appending to a
+/// compressed file adds another stream, which is completed by `Output::finish`.
+pub fn create_file<P: AsRef<Path>>(path: P, append: bool) -> Result<Output, std::io::Error> {
+	let path = path.as_ref();
+	let file = OpenOptions::new()
+		.create(true)
+		.write(true)
+		.append(append)
+		.truncate(!append)
+		.open(path)?;
+
+	Ok(Output(
+		match path.extension().and_then(|ext| ext.to_str()) {
+			Some("gz") => Inner::Gzip(BufWriter::new(GzEncoder::new(file, Compression::default()))),
+			Some("zst") => Inner::Zstd(BufWriter::new(zstd::Encoder::new(file, 0)?)),
+			_ => Inner::Plain(BufWriter::new(file)),
+		},
+	))
+}